@@ -0,0 +1,381 @@
+// src/analysis.rs
+
+use crate::builtins;
+use crate::callee::Callee;
+use crate::codes::Code;
+use crate::diagnostics::Diagnostics;
+use crate::native::Signature;
+use crate::parser::{Arithmetic, Expr, ExprKind, Function, Program, Stmt, StmtKind};
+use crate::span::Span;
+use std::collections::HashSet;
+
+/// Check a program without running any of it: resolve the variables and functions each
+/// function refers to, check call arity, and flag unreachable code and unused names.
+/// Problems are reported in source order per function.
+pub fn check(prog: &Program, diags: &mut Diagnostics) {
+    check_with(prog, &[], &[], diags);
+}
+
+/// Like `check`, also resolving calls to the functions a host provides and reads of the
+/// global variables it sets
+pub fn check_with(
+    prog: &Program,
+    natives: &[Signature],
+    globals: &[String],
+    diags: &mut Diagnostics,
+) {
+    let mut names: Vec<&String> = prog.functions.keys().collect();
+    names.sort();
+    for name in names {
+        let func = &prog.functions[name];
+        let params: HashSet<&str> = func.params.iter().map(|p| p.name.as_str()).collect();
+        let mut bound = params.clone();
+        collect_bindings(&func.body, &mut bound);
+        let mut resolver = Resolver {
+            prog,
+            natives,
+            globals,
+            func,
+            diags: &mut *diags,
+            bound,
+            assigned: params,
+            read: HashSet::new(),
+            bindings: Vec::new(),
+        };
+        resolver.check_block(&func.body);
+        resolver.report_unused();
+    }
+}
+
+struct Resolver<'a> {
+    prog: &'a Program,
+    natives: &'a [Signature],
+    /// Variables every function can read, unless a param of the same name shadows them
+    globals: &'a [String],
+    func: &'a Function,
+    diags: &'a mut Diagnostics,
+    /// Names given a value anywhere in the function: params, assignments and loop counters
+    bound: HashSet<&'a str>,
+    /// Names given a value so far, in document order
+    assigned: HashSet<&'a str>,
+    /// Names read anywhere in the function
+    read: HashSet<&'a str>,
+    /// Each local variable with the statement that first declares or assigns it
+    bindings: Vec<(&'a str, Span)>,
+}
+
+impl<'a> Resolver<'a> {
+    /// Check statements in order; returns whether the block always leaves early
+    fn check_block(&mut self, stmts: &'a [Stmt]) -> bool {
+        let mut exits = false;
+        let mut warned = false;
+        for stmt in stmts {
+            if exits && !warned {
+                self.diags.warning(
+                    &self.func.file,
+                    stmt.span,
+                    Code::Unreachable,
+                    "Unreachable code".to_string(),
+                );
+                warned = true;
+            }
+            // unreachable statements are still checked so their problems show up too
+            exits |= self.check_stmt(stmt);
+        }
+        exits
+    }
+
+    /// Check one statement; returns whether control never continues past it
+    fn check_stmt(&mut self, stmt: &'a Stmt) -> bool {
+        match &stmt.kind {
+            StmtKind::Var { name, .. } => self.bind(name, stmt.span),
+            StmtKind::Break | StmtKind::Continue => return true,
+            StmtKind::Assign { var, expr } => {
+                self.check_expr(expr);
+                self.assigned.insert(var);
+                // assigning a global updates it rather than creating a local
+                if !self.is_global(var) {
+                    self.bind(var, stmt.span);
+                }
+            }
+            StmtKind::Print(expr) => self.check_expr(expr),
+            StmtKind::Return(expr) => {
+                self.check_expr(expr);
+                return true;
+            }
+            StmtKind::Call(name, args) => self.check_call(name, args, stmt.span),
+            StmtKind::If {
+                cond,
+                then_body,
+                else_body,
+            } => {
+                self.check_expr(cond);
+                let then_exits = self.check_block(then_body);
+                let else_exits = self.check_block(else_body);
+                return then_exits && else_exits;
+            }
+            StmtKind::Loop { var, count, body } => {
+                self.check_expr(count);
+                if let Some(var) = var {
+                    self.assigned.insert(var);
+                    self.bind(var, stmt.span);
+                }
+                self.check_block(body);
+            }
+            StmtKind::For {
+                var,
+                from,
+                to,
+                step,
+                body,
+                ..
+            } => {
+                self.check_expr(from);
+                self.check_expr(to);
+                self.check_expr(step);
+                // the counter is required syntax, so an unused one is not reported
+                self.assigned.insert(var);
+                self.check_block(body);
+            }
+            StmtKind::While { cond, body } => {
+                self.check_expr(cond);
+                self.check_block(body);
+            }
+        }
+        false
+    }
+
+    fn is_global(&self, name: &str) -> bool {
+        self.globals.iter().any(|g| g == name) && !self.func.params.iter().any(|p| p.name == name)
+    }
+
+    /// Remember where a local variable first appears
+    fn bind(&mut self, name: &'a str, span: Span) {
+        if !self.bindings.iter().any(|&(n, _)| n == name) {
+            self.bindings.push((name, span));
+        }
+    }
+
+    /// Warn about variables and params that are never read; names starting with `_` are exempt
+    fn report_unused(&mut self) {
+        let unused = |name: &str| !name.starts_with('_') && !self.read.contains(name);
+        let mut found = Vec::new();
+        for p in &self.func.params {
+            if unused(&p.name) {
+                found.push((
+                    Code::UnusedParameter,
+                    format!("Parameter {} is never used", p.name),
+                    p.span,
+                ));
+            }
+        }
+        for &(name, span) in &self.bindings {
+            let is_param = self.func.params.iter().any(|p| p.name == name);
+            if unused(name) && !is_param {
+                found.push((
+                    Code::UnusedVariable,
+                    format!("Variable {} is never used", name),
+                    span,
+                ));
+            }
+        }
+        for (code, msg, span) in found {
+            self.diags.warning(&self.func.file, span, code, msg);
+        }
+    }
+
+    fn check_expr(&mut self, expr: &'a Expr) {
+        match &expr.kind {
+            ExprKind::VarRef(name) => {
+                self.read.insert(name);
+                if self.assigned.contains(name.as_str()) || self.is_global(name) {
+                    return;
+                }
+                let file = &self.func.file;
+                if self.bound.contains(name.as_str()) {
+                    // a loop may still assign it on an earlier iteration
+                    self.diags.warning(
+                        file,
+                        expr.span,
+                        Code::MaybeUnassigned,
+                        format!("Variable {} may be used before it is assigned", name),
+                    );
+                } else {
+                    let globals = self.globals.iter().map(String::as_str);
+                    let bound = self.bound.iter().copied().chain(globals);
+                    self.diags
+                        .error(
+                            file,
+                            expr.span,
+                            Code::UndefinedVariable,
+                            format!("Undefined variable {}", name),
+                        )
+                        .suggest(name, bound);
+                }
+            }
+            ExprKind::Call(name, args) => self.check_call(name, args, expr.span),
+            ExprKind::BinaryOp(_, l, r) => {
+                self.check_expr(l);
+                self.check_expr(r);
+            }
+            ExprKind::UnaryOp(_, e) => self.check_expr(e),
+            ExprKind::LiteralBigInt(i) if self.prog.arithmetic != Arithmetic::Bigint => {
+                self.diags.error(
+                    &self.func.file,
+                    expr.span,
+                    Code::Overflow,
+                    format!(
+                        "Integer literal {} does not fit in 64 bits; use arithmetic=\"bigint\"",
+                        i
+                    ),
+                );
+            }
+            ExprKind::LiteralInt(_)
+            | ExprKind::LiteralBigInt(_)
+            | ExprKind::LiteralFloat(_)
+            | ExprKind::LiteralDecimal(_)
+            | ExprKind::LiteralStr(_)
+            | ExprKind::LiteralBool(_)
+            | ExprKind::LiteralNull => {}
+        }
+    }
+
+    fn check_call(&mut self, name: &str, args: &'a [Expr], span: Span) {
+        for arg in args {
+            self.check_expr(arg);
+        }
+        let file = &self.func.file;
+        let natives = self.natives;
+        let callee = Callee::lookup(self.prog, name, |n| natives.iter().find(|s| s.name == n));
+        if let Some(callee) = callee {
+            if let Err(msg) = callee.check_arity(args.len()) {
+                let diag = self.diags.error(file, span, Code::ArgumentCount, msg);
+                diag.note(format!("declared as {}", callee.label()));
+                if let Callee::Script(func) = callee {
+                    diag.label(
+                        &func.file,
+                        func.span,
+                        format!("function {} is defined here", name),
+                    );
+                }
+            }
+        } else {
+            let known = self.prog.functions.keys().map(String::as_str);
+            let natives = self.natives.iter().map(|n| n.name.as_str());
+            let builtins = builtins::BUILTINS.iter().map(|b| b.name);
+            self.diags
+                .error(
+                    file,
+                    span,
+                    Code::UndefinedFunction,
+                    format!("Undefined function {}", name),
+                )
+                .suggest(name, known.chain(natives).chain(builtins));
+        }
+    }
+}
+
+/// Names assigned anywhere in a block, including loop counters in nested blocks
+fn collect_bindings<'a>(stmts: &'a [Stmt], out: &mut HashSet<&'a str>) {
+    for stmt in stmts {
+        match &stmt.kind {
+            StmtKind::Assign { var, .. } => {
+                out.insert(var);
+            }
+            StmtKind::If {
+                then_body,
+                else_body,
+                ..
+            } => {
+                collect_bindings(then_body, out);
+                collect_bindings(else_body, out);
+            }
+            StmtKind::Loop { var, body, .. } => {
+                if let Some(var) = var {
+                    out.insert(var);
+                }
+                collect_bindings(body, out);
+            }
+            StmtKind::For { var, body, .. } => {
+                out.insert(var);
+                collect_bindings(body, out);
+            }
+            StmtKind::While { body, .. } => collect_bindings(body, out),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_file;
+
+    #[test]
+    fn reports_undefined_names_and_arity() {
+        let src = "<program name=\"t\" version=\"1.0\">\n\
+             <function name=\"add\"><param name=\"a\"/><param name=\"b\"/>\n\
+             <body><return>a + b</return></body></function>\n\
+             <function name=\"main\"><body>\n\
+             <print>total</print>\n\
+             <assign var=\"total\">add(1)</assign>\n\
+             <print>nope(total) + missing</print>\n\
+             <call function=\"upper\"/>\n\
+             </body></function></program>";
+        let path = std::env::temp_dir().join("analysis_errors.xpl");
+        std::fs::write(&path, src).unwrap();
+        let prog = parse_file(path.to_str().unwrap()).unwrap();
+        let mut diags = Diagnostics::new();
+        check(&prog, &mut diags);
+        let found: Vec<String> = diags
+            .iter()
+            .map(|d| format!("{} {}: {}", d.span.start, d.severity, d.msg))
+            .collect();
+        assert_eq!(
+            found,
+            [
+                "5:8 warning: Variable total may be used before it is assigned",
+                "6:21 error: Expected 2 args for function 'add', got 1",
+                "7:8 error: Undefined function nope",
+                "7:22 error: Undefined variable missing",
+                "8:1 error: Expected 1 args for function 'upper', got 0",
+            ]
+        );
+        let arity = &diags.iter().nth(1).unwrap().labels[0];
+        assert_eq!(arity.span.start.to_string(), "2:1");
+        assert_eq!(arity.msg, "function add is defined here");
+        let notes: Vec<&String> = diags.iter().flat_map(|d| &d.notes).collect();
+        assert_eq!(notes, ["declared as add(a, b)", "declared as upper(s)"]);
+    }
+
+    #[test]
+    fn flags_unreachable_code_and_unused_names() {
+        let src = "<program name=\"t\" version=\"1.0\">\n\
+             <function name=\"pick\"><param name=\"a\"/><param name=\"unused\"/><param name=\"_skip\"/>\n\
+             <body><if><condition>a</condition><then><return>1</return></then>\n\
+             <else><return>2</return></else></if>\n\
+             <print>\"never\"</print><print>\"reported once\"</print></body></function>\n\
+             <function name=\"main\"><body>\n\
+             <var name=\"spare\"/><assign var=\"kept\">pick(1, 2, 3)</assign>\n\
+             <for var=\"i\" from=\"0\" to=\"2\"><print>kept</print><break/><print>i</print></for>\n\
+             </body></function></program>";
+        let path = std::env::temp_dir().join("analysis_warnings.xpl");
+        std::fs::write(&path, src).unwrap();
+        let prog = parse_file(path.to_str().unwrap()).unwrap();
+        let mut diags = Diagnostics::new();
+        check(&prog, &mut diags);
+        let found: Vec<String> = diags
+            .iter()
+            .map(|d| format!("{} {}: {}", d.span.start, d.severity, d.msg))
+            .collect();
+        assert_eq!(
+            found,
+            [
+                "8:57 warning: Unreachable code",
+                "7:1 warning: Variable spare is never used",
+                "5:1 warning: Unreachable code",
+                "2:40 warning: Parameter unused is never used",
+            ]
+        );
+    }
+}
@@ -1,18 +1,22 @@
 // src/bin/xpl_ls.rs
 
+use std::collections::HashMap;
 use tower_lsp::lsp_types::{
-    CodeLens, CodeLensOptions, CodeLensParams, Command, CompletionItem, CompletionOptions,
-    CompletionParams, CompletionResponse, Diagnostic, DiagnosticSeverity,
-    DidOpenTextDocumentParams, DidSaveTextDocumentParams, Documentation, GotoDefinitionParams,
-    GotoDefinitionResponse, Hover, HoverContents, HoverParams, InitializeParams, InitializeResult,
-    InitializedParams, Location, MarkedString, MessageType, OneOf, ParameterInformation,
-    ParameterLabel, Position, Range, ReferenceParams, ServerCapabilities, SignatureHelp,
-    SignatureHelpOptions, SignatureHelpParams, SignatureInformation, TextDocumentSyncCapability,
-    TextDocumentSyncKind, Url,
+    CodeDescription, CodeLens, CodeLensOptions, CodeLensParams, Command, CompletionItem,
+    CompletionOptions, CompletionParams, CompletionResponse, Diagnostic,
+    DiagnosticRelatedInformation, DiagnosticSeverity, DidOpenTextDocumentParams,
+    DidSaveTextDocumentParams, Documentation, GotoDefinitionParams, GotoDefinitionResponse, Hover,
+    HoverContents, HoverParams, InitializeParams, InitializeResult, InitializedParams, Location,
+    MarkedString, MessageType, NumberOrString, OneOf, ParameterInformation, ParameterLabel,
+    Position, Range, ReferenceParams, ServerCapabilities, SignatureHelp, SignatureHelpOptions,
+    SignatureHelpParams, SignatureInformation, TextDocumentSyncCapability, TextDocumentSyncKind,
+    Url,
 };
 use tower_lsp::{Client, LanguageServer, LspService, Server};
-use xpl::XplError;
-use xpl::parser;
+use xpl::callee::Callee;
+use xpl::parser::Arithmetic;
+use xpl::{Severity, Span};
+use xpl::{native, parser};
 
 #[tokio::main]
 async fn main() {
@@ -26,6 +30,32 @@ struct Backend {
     client: Client,
 }
 
+impl Backend {
+    /// Publish every problem in the document's file, all at once.
+    /// The script is only analyzed, never run, so saving a file has no side effects.
+    async fn publish_diagnostics(&self, uri: Url) {
+        let path = uri.to_file_path().unwrap();
+        let path = path.to_str().unwrap();
+        let natives = self.natives(path).await;
+        let diags = xpl::check_file_with(path, &natives);
+        let diagnostics = diags.iter().map(|d| to_lsp_diagnostic(d, path)).collect();
+        self.client
+            .publish_diagnostics(uri, diagnostics, None)
+            .await;
+    }
+
+    /// Functions the host application declares for the script in its signatures file
+    async fn natives(&self, path: &str) -> Vec<xpl::Signature> {
+        match native::signatures_for(path) {
+            Ok(sigs) => sigs,
+            Err(e) => {
+                self.client.log_message(MessageType::ERROR, e).await;
+                Vec::new()
+            }
+        }
+    }
+}
+
 #[tower_lsp::async_trait]
 impl LanguageServer for Backend {
     async fn initialize(
@@ -81,42 +111,40 @@ impl LanguageServer for Backend {
         if let Some(tok) = token {
             // search in current file
             for (i, line) in src.lines().enumerate() {
-                if line.contains(&format!("<function name=\"{}\"", tok)) {
-                    if let Some(col) = line.find(&tok) {
-                        let loc = Location {
-                            uri: uri.clone(),
-                            range: Range {
-                                start: Position::new(i as u32, col as u32),
-                                end: Position::new(i as u32, (col + tok.len()) as u32),
-                            },
-                        };
-                        return Ok(Some(GotoDefinitionResponse::Scalar(loc)));
-                    }
+                if line.contains(&format!("<function name=\"{}\"", tok))
+                    && let Some(col) = line.find(&tok)
+                {
+                    let loc = Location {
+                        uri: uri.clone(),
+                        range: Range {
+                            start: Position::new(i as u32, col as u32),
+                            end: Position::new(i as u32, (col + tok.len()) as u32),
+                        },
+                    };
+                    return Ok(Some(GotoDefinitionResponse::Scalar(loc)));
                 }
             }
             // search other .xpl files in the same directory
-            if let Some(dir) = path.parent() {
-                if let Ok(entries) = std::fs::read_dir(dir) {
-                    for entry in entries.flatten() {
-                        let pth = entry.path();
-                        if pth.extension().and_then(|e| e.to_str()) == Some("xpl") {
-                            let content = std::fs::read_to_string(&pth).unwrap_or_default();
-                            for (j, l) in content.lines().enumerate() {
-                                if l.contains(&format!("<function name=\"{}\"", tok)) {
-                                    if let Some(start) = l.find(&tok) {
-                                        let uri2 = Url::from_file_path(&pth).unwrap();
-                                        let range = Range {
-                                            start: Position::new(j as u32, start as u32),
-                                            end: Position::new(
-                                                j as u32,
-                                                (start + tok.len()) as u32,
-                                            ),
-                                        };
-                                        return Ok(Some(GotoDefinitionResponse::Scalar(
-                                            Location { uri: uri2, range },
-                                        )));
-                                    }
-                                }
+            if let Some(dir) = path.parent()
+                && let Ok(entries) = std::fs::read_dir(dir)
+            {
+                for entry in entries.flatten() {
+                    let pth = entry.path();
+                    if pth.extension().and_then(|e| e.to_str()) == Some("xpl") {
+                        let content = std::fs::read_to_string(&pth).unwrap_or_default();
+                        for (j, l) in content.lines().enumerate() {
+                            if l.contains(&format!("<function name=\"{}\"", tok))
+                                && let Some(start) = l.find(&tok)
+                            {
+                                let uri2 = Url::from_file_path(&pth).unwrap();
+                                let range = Range {
+                                    start: Position::new(j as u32, start as u32),
+                                    end: Position::new(j as u32, (start + tok.len()) as u32),
+                                };
+                                return Ok(Some(GotoDefinitionResponse::Scalar(Location {
+                                    uri: uri2,
+                                    range,
+                                })));
                             }
                         }
                     }
@@ -178,42 +206,22 @@ impl LanguageServer for Backend {
         {
             col -= 1;
         }
-        if col > 0 {
-            let token = get_token_at(&src, pos.line as usize, col - 1);
-            if let Some(tok) = token {
-                if let Ok(prog) = parser::parse_file(path.to_str().unwrap()) {
-                    if let Some(f) = prog.functions.get(&tok) {
-                        let sig_label = format!(
-                            "{}({})",
-                            f.name,
-                            f.params
-                                .iter()
-                                .map(|p| p.name.clone())
-                                .collect::<Vec<_>>()
-                                .join(", ")
-                        );
-                        let parameters = f
-                            .params
-                            .iter()
-                            .map(|p| ParameterInformation {
-                                label: ParameterLabel::Simple(p.name.clone()),
-                                documentation: p.description.clone().map(Documentation::String),
-                            })
-                            .collect();
-                        let sign = SignatureInformation {
-                            label: sig_label,
-                            documentation: f.description.clone().map(Documentation::String),
-                            parameters: Some(parameters),
-                            active_parameter: Some(0),
-                        };
-                        return Ok(Some(SignatureHelp {
-                            signatures: vec![sign],
-                            active_signature: Some(0),
-                            active_parameter: Some(0),
-                        }));
-                    }
-                }
-            }
+        if col == 0 {
+            return Ok(None);
+        }
+        let Some(tok) = get_token_at(&src, pos.line as usize, col - 1) else {
+            return Ok(None);
+        };
+        let path = path.to_str().unwrap();
+        let prog = parse_or_empty(path);
+        let natives = self.natives(path).await;
+        if let Some(callee) = Callee::lookup(&prog, &tok, |n| natives.iter().find(|s| s.name == n))
+        {
+            return Ok(Some(SignatureHelp {
+                signatures: vec![signature_information(callee)],
+                active_signature: Some(0),
+                active_parameter: Some(0),
+            }));
         }
         Ok(None)
     }
@@ -223,8 +231,7 @@ impl LanguageServer for Backend {
         _params: CodeLensParams,
     ) -> tower_lsp::jsonrpc::Result<Option<Vec<CodeLens>>> {
         // URI not needed here
-        let mut lenses = Vec::new();
-        lenses.push(CodeLens {
+        let lenses = vec![CodeLens {
             range: Range::new(Position::new(0, 0), Position::new(0, 0)),
             command: Some(Command {
                 title: "Run".to_string(),
@@ -232,80 +239,16 @@ impl LanguageServer for Backend {
                 arguments: None,
             }),
             data: None,
-        });
+        }];
         Ok(Some(lenses))
     }
 
     async fn did_open(&self, params: DidOpenTextDocumentParams) {
-        let uri = params.text_document.uri.clone();
-        let path = uri.to_file_path().unwrap();
-        let diagnostics = match xpl::run_file(path.to_str().unwrap()) {
-            Ok(_) => Vec::new(),
-            Err(e) => {
-                let (line0, col0, msg) = match &e {
-                    XplError::Semantic { line, col, msg, .. } => (
-                        (*line).saturating_sub(1) as u32,
-                        (*col).saturating_sub(1) as u32,
-                        msg.clone(),
-                    ),
-                    XplError::Xml { .. } | XplError::Io { .. } => (0, 0, e.to_string()),
-                };
-                let range = Range {
-                    start: Position::new(line0, col0),
-                    end: Position::new(line0, col0 + 1),
-                };
-                vec![Diagnostic {
-                    range,
-                    severity: Some(DiagnosticSeverity::ERROR),
-                    code: None,
-                    code_description: None,
-                    source: Some("xpl".to_string()),
-                    message: msg,
-                    tags: None,
-                    related_information: None,
-                    data: None,
-                }]
-            }
-        };
-        self.client
-            .publish_diagnostics(uri, diagnostics, None)
-            .await;
+        self.publish_diagnostics(params.text_document.uri).await;
     }
 
     async fn did_save(&self, params: DidSaveTextDocumentParams) {
-        let uri = params.text_document.uri.clone();
-        let path = uri.to_file_path().unwrap();
-        let diagnostics = match xpl::run_file(path.to_str().unwrap()) {
-            Ok(_) => Vec::new(),
-            Err(e) => {
-                let (line0, col0, msg) = match &e {
-                    XplError::Semantic { line, col, msg, .. } => (
-                        (*line).saturating_sub(1) as u32,
-                        (*col).saturating_sub(1) as u32,
-                        msg.clone(),
-                    ),
-                    _ => (0, 0, e.to_string()),
-                };
-                let range = Range {
-                    start: Position::new(line0, col0),
-                    end: Position::new(line0, col0 + 1),
-                };
-                vec![Diagnostic {
-                    range,
-                    severity: Some(DiagnosticSeverity::ERROR),
-                    code: None,
-                    code_description: None,
-                    source: Some("xpl".to_string()),
-                    message: msg,
-                    tags: None,
-                    related_information: None,
-                    data: None,
-                }]
-            }
-        };
-        self.client
-            .publish_diagnostics(uri, diagnostics, None)
-            .await;
+        self.publish_diagnostics(params.text_document.uri).await;
     }
 
     async fn hover(&self, params: HoverParams) -> tower_lsp::jsonrpc::Result<Option<Hover>> {
@@ -326,23 +269,19 @@ impl LanguageServer for Backend {
         }
         let token = &line[start..end];
         if let Ok(prog) = parser::parse_file(path.to_str().unwrap()) {
-            if let Some(func) = prog.functions.get(token) {
+            let natives = self.natives(path.to_str().unwrap()).await;
+            let lookup = |n: &str| natives.iter().find(|s| s.name == n);
+            if let Some(callee) = Callee::lookup(&prog, token, lookup) {
                 let mut contents = String::new();
                 if let Some(desc) = &prog.description {
                     contents.push_str(&format!("**Program**: {}\n\n", desc));
                 }
-                if let Some(fdesc) = &func.description {
-                    contents.push_str(&format!("**Function** {}**:** {}\n\n", func.name, fdesc));
+                if let Some(fdesc) = callee.description() {
+                    contents.push_str(&format!("**Function** {}**:** {}\n\n", token, fdesc));
                 } else {
-                    contents.push_str(&format!("**Function** {}\n\n", func.name));
+                    contents.push_str(&format!("**Function** {}\n\n", token));
                 }
-                let params = func
-                    .params
-                    .iter()
-                    .map(|p| p.name.clone())
-                    .collect::<Vec<_>>()
-                    .join(", ");
-                contents.push_str(&format!("Signature: {}({})", func.name, params));
+                contents.push_str(&format!("Signature: {}", callee.label()));
                 return Ok(Some(Hover {
                     contents: HoverContents::Scalar(MarkedString::String(contents)),
                     range: None,
@@ -423,6 +362,43 @@ impl LanguageServer for Backend {
 }
 
 // helper to extract word at line,col
+/// The script's functions, or none when it does not parse, so natives and builtins still resolve
+fn parse_or_empty(path: &str) -> parser::Program {
+    parser::parse_file(path).unwrap_or_else(|_| parser::Program {
+        description: None,
+        functions: HashMap::new(),
+        arithmetic: Arithmetic::default(),
+    })
+}
+
+/// Signature help for a call, labelled the same way for every kind of callee
+fn signature_information(callee: Callee) -> SignatureInformation {
+    let parameters = callee
+        .params()
+        .into_iter()
+        .enumerate()
+        .map(|(i, (name, _))| ParameterInformation {
+            label: ParameterLabel::Simple(name.to_string()),
+            // only script functions describe their params
+            documentation: match callee {
+                Callee::Script(func) => func.params[i]
+                    .description
+                    .clone()
+                    .map(Documentation::String),
+                _ => None,
+            },
+        })
+        .collect();
+    SignatureInformation {
+        label: callee.label(),
+        documentation: callee
+            .description()
+            .map(|d| Documentation::String(d.to_string())),
+        parameters: Some(parameters),
+        active_parameter: Some(0),
+    }
+}
+
 fn get_token_at(src: &str, line: usize, col: usize) -> Option<String> {
     let l = src.lines().nth(line)?;
     let is_word = |c: char| c.is_alphanumeric() || c == '_';
@@ -436,3 +412,94 @@ fn get_token_at(src: &str, line: usize, col: usize) -> Option<String> {
     }
     Some(l[start..end].to_string())
 }
+
+fn to_lsp_diagnostic(diag: &xpl::Diagnostic, path: &str) -> Diagnostic {
+    let severity = match diag.severity {
+        Severity::Error => DiagnosticSeverity::ERROR,
+        Severity::Warning => DiagnosticSeverity::WARNING,
+        Severity::Note => DiagnosticSeverity::INFORMATION,
+    };
+    // problems in other files, such as included libraries, are shown at the top of this one
+    let (range, mut message) = if diag.file == path {
+        (span_range(diag.span), diag.msg.clone())
+    } else {
+        (Range::default(), diag.to_string())
+    };
+    for note in &diag.notes {
+        message.push_str(&format!("\nnote: {}", note));
+    }
+    if let Some(name) = &diag.suggestion {
+        message.push_str(&format!("\nhelp: did you mean `{}`?", name));
+    }
+    // labels whose file cannot be turned into a URI are dropped
+    let related: Vec<DiagnosticRelatedInformation> = diag
+        .labels
+        .iter()
+        .filter_map(|label| {
+            let file = std::fs::canonicalize(&label.file).ok()?;
+            Some(DiagnosticRelatedInformation {
+                location: Location::new(Url::from_file_path(file).ok()?, span_range(label.span)),
+                message: label.msg.clone(),
+            })
+        })
+        .collect();
+    let info = diag.code.explanation();
+    Diagnostic {
+        range,
+        severity: Some(severity),
+        code: Some(NumberOrString::String(info.id.to_string())),
+        code_description: Url::parse(&info.url())
+            .ok()
+            .map(|href| CodeDescription { href }),
+        source: Some("xpl".to_string()),
+        message,
+        tags: None,
+        related_information: (!related.is_empty()).then_some(related),
+        data: None,
+    }
+}
+
+// spans are 1-based; an empty or unknown end still underlines one character
+fn span_range(span: Span) -> Range {
+    let pos = |line: usize, col: usize| {
+        Position::new(line.saturating_sub(1) as u32, col.saturating_sub(1) as u32)
+    };
+    let start = pos(span.start.line, span.start.col);
+    let end = if span.end > span.start {
+        pos(span.end.line, span.end.col)
+    } else {
+        Position::new(start.line, start.character + 1)
+    };
+    Range { start, end }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn script_functions_are_labelled_like_natives() {
+        let prog = parser::parse_str(
+            "<program name=\"p\"><function name=\"add\"><description>Sum</description>\
+             <param name=\"a\" type=\"int\"><description>First</description></param>\
+             <param name=\"b\"/><return type=\"int\"/><body><return>a + b</return></body>\
+             </function></program>",
+            "<ls>",
+        )
+        .unwrap();
+        let info = signature_information(Callee::Script(&prog.functions["add"]));
+        assert_eq!(info.label, "add(a: int, b) -> int");
+        assert!(matches!(info.documentation, Some(Documentation::String(d)) if d == "Sum"));
+        let params = info.parameters.unwrap();
+        assert!(matches!(&params[0].documentation, Some(Documentation::String(d)) if d == "First"));
+        assert!(params[1].documentation.is_none());
+        let sig = native::Signature::new("add")
+            .param("a", "int")
+            .param("b", "any")
+            .returns("int");
+        assert_eq!(
+            signature_information(Callee::Native(&sig)).label,
+            sig.label()
+        );
+    }
+}
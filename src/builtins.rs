@@ -0,0 +1,286 @@
+// src/builtins.rs
+
+use crate::value::Value;
+use num_bigint::BigInt;
+use rust_decimal::Decimal;
+
+/// A function provided by the runtime rather than by a script
+pub struct Builtin {
+    pub name: &'static str,
+    pub params: &'static [&'static str],
+    /// Number of leading params that must be supplied
+    pub required: usize,
+    pub description: &'static str,
+}
+
+pub const BUILTINS: &[Builtin] = &[
+    Builtin {
+        name: "len",
+        params: &["value"],
+        required: 1,
+        description: "Number of characters in a string or items in a list.",
+    },
+    Builtin {
+        name: "substr",
+        params: &["s", "start", "length"],
+        required: 2,
+        description: "Characters of `s` from `start`, up to `length` of them or to the end.",
+    },
+    Builtin {
+        name: "upper",
+        params: &["s"],
+        required: 1,
+        description: "`s` converted to upper case.",
+    },
+    Builtin {
+        name: "lower",
+        params: &["s"],
+        required: 1,
+        description: "`s` converted to lower case.",
+    },
+    Builtin {
+        name: "trim",
+        params: &["s"],
+        required: 1,
+        description: "`s` without leading and trailing whitespace.",
+    },
+    Builtin {
+        name: "split",
+        params: &["s", "sep"],
+        required: 1,
+        description: "List of the parts of `s` separated by `sep`, or by whitespace when omitted.",
+    },
+    Builtin {
+        name: "contains",
+        params: &["s", "sub"],
+        required: 2,
+        description: "Whether `s` contains `sub`.",
+    },
+    Builtin {
+        name: "replace",
+        params: &["s", "from", "to"],
+        required: 3,
+        description: "`s` with every occurrence of `from` replaced by `to`.",
+    },
+    Builtin {
+        name: "get",
+        params: &["list", "index"],
+        required: 2,
+        description: "Item of `list` at zero-based `index`.",
+    },
+    Builtin {
+        name: "assert",
+        params: &["cond", "message"],
+        required: 1,
+        description: "Fail with `message` unless `cond` is truthy; used by `xpl test`.",
+    },
+    Builtin {
+        name: "int",
+        params: &["value"],
+        required: 1,
+        description: "`value` as an int: numbers are truncated toward zero, strings are parsed.",
+    },
+    Builtin {
+        name: "float",
+        params: &["value"],
+        required: 1,
+        description: "`value` as a float, the nearest one for ints and decimals; strings are parsed.",
+    },
+    Builtin {
+        name: "decimal",
+        params: &["value"],
+        required: 1,
+        description: "`value` as a decimal, the shortest one for floats; strings are parsed.",
+    },
+    Builtin {
+        name: "args",
+        params: &[],
+        required: 0,
+        description: "List of the command-line arguments passed to the script.",
+    },
+];
+
+impl Builtin {
+    /// Whether the builtin can be called with `n` arguments
+    pub fn accepts(&self, n: usize) -> bool {
+        n >= self.required && n <= self.params.len()
+    }
+
+    /// Accepted argument count for messages, e.g. `2` or `1 to 2`
+    pub fn arity(&self) -> String {
+        if self.required == self.params.len() {
+            self.required.to_string()
+        } else {
+            format!("{} to {}", self.required, self.params.len())
+        }
+    }
+}
+
+/// Look up a builtin by name
+pub fn lookup(name: &str) -> Option<&'static Builtin> {
+    BUILTINS.iter().find(|b| b.name == name)
+}
+
+/// Invoke a builtin with arguments already checked against its arity
+pub fn call(name: &str, args: &[Value]) -> Result<Value, String> {
+    match name {
+        "len" => match &args[0] {
+            Value::Str(s) => Ok(Value::Int(s.chars().count() as i64)),
+            Value::List(items) => Ok(Value::Int(items.len() as i64)),
+            other => Err(format!(
+                "len expects a string or list, got {}",
+                other.type_name()
+            )),
+        },
+        "substr" => {
+            let s = string_arg(name, args, 0)?;
+            let start = index_arg(name, args, 1)?;
+            let rest = s.chars().skip(start);
+            let out: String = match args.get(2) {
+                Some(_) => rest.take(index_arg(name, args, 2)?).collect(),
+                None => rest.collect(),
+            };
+            Ok(Value::Str(out))
+        }
+        "upper" => Ok(Value::Str(string_arg(name, args, 0)?.to_uppercase())),
+        "lower" => Ok(Value::Str(string_arg(name, args, 0)?.to_lowercase())),
+        "trim" => Ok(Value::Str(string_arg(name, args, 0)?.trim().to_string())),
+        "split" => {
+            let s = string_arg(name, args, 0)?;
+            let parts: Vec<Value> = match args.get(1) {
+                Some(_) => {
+                    let sep = string_arg(name, args, 1)?;
+                    if sep.is_empty() {
+                        return Err("split separator must not be empty".to_string());
+                    }
+                    s.split(sep).map(|p| Value::Str(p.to_string())).collect()
+                }
+                None => s
+                    .split_whitespace()
+                    .map(|p| Value::Str(p.to_string()))
+                    .collect(),
+            };
+            Ok(Value::List(parts))
+        }
+        "contains" => {
+            let s = string_arg(name, args, 0)?;
+            Ok(Value::Bool(s.contains(string_arg(name, args, 1)?)))
+        }
+        "replace" => {
+            let s = string_arg(name, args, 0)?;
+            let from = string_arg(name, args, 1)?;
+            if from.is_empty() {
+                return Err("replace pattern must not be empty".to_string());
+            }
+            Ok(Value::Str(s.replace(from, string_arg(name, args, 2)?)))
+        }
+        "get" => {
+            let Value::List(items) = &args[0] else {
+                return Err(format!("get expects a list, got {}", args[0].type_name()));
+            };
+            let index = index_arg(name, args, 1)?;
+            items.get(index).cloned().ok_or_else(|| {
+                format!(
+                    "Index {} out of range for list of length {}",
+                    index,
+                    items.len()
+                )
+            })
+        }
+        "assert" => match args.get(1) {
+            _ if args[0].is_truthy() => Ok(Value::Null),
+            Some(msg) => Err(format!("Assertion failed: {}", msg)),
+            None => Err("Assertion failed".to_string()),
+        },
+        "int" => match &args[0] {
+            Value::Int(_) | Value::BigInt(_) => Ok(args[0].clone()),
+            Value::Float(f) if f.is_finite() => Ok(parse_int(&format!("{:.0}", f.trunc()))),
+            Value::Decimal(d) => Ok(parse_int(&d.trunc().to_string())),
+            Value::Str(s) => s
+                .trim()
+                .parse()
+                .map(Value::from_bigint)
+                .map_err(|_| format!("int cannot parse \"{}\"", s)),
+            Value::Float(f) => Err(format!("int cannot convert {}", f)),
+            other => Err(format!(
+                "int expects a number or string, got {}",
+                other.type_name()
+            )),
+        },
+        "float" => match &args[0] {
+            Value::Int(i) => Ok(Value::Float(*i as f64)),
+            Value::Float(_) => Ok(args[0].clone()),
+            // the display form of a number always parses as f64
+            Value::BigInt(_) | Value::Decimal(_) => {
+                Ok(Value::Float(args[0].to_string().parse().unwrap()))
+            }
+            Value::Str(s) => s
+                .trim()
+                .parse()
+                .map(Value::Float)
+                .map_err(|_| format!("float cannot parse \"{}\"", s)),
+            other => Err(format!(
+                "float expects a number or string, got {}",
+                other.type_name()
+            )),
+        },
+        "decimal" => {
+            let d = match &args[0] {
+                Value::Int(i) => Some(Decimal::from(*i)),
+                Value::BigInt(i) => Decimal::from_str_exact(&i.to_string()).ok(),
+                Value::Float(f) => Decimal::try_from(*f).ok(),
+                Value::Decimal(d) => Some(*d),
+                Value::Str(s) => {
+                    return Decimal::from_str_exact(s.trim())
+                        .map(Value::Decimal)
+                        .map_err(|_| format!("decimal cannot parse \"{}\"", s));
+                }
+                other => {
+                    return Err(format!(
+                        "decimal expects a number or string, got {}",
+                        other.type_name()
+                    ));
+                }
+            };
+            // the range of decimals is about ±7.9e28
+            d.map(Value::Decimal)
+                .ok_or_else(|| format!("decimal cannot convert {}", args[0]))
+        }
+        _ => Err(format!("Unknown builtin {}", name)),
+    }
+}
+
+/// An int from text that is known to be an optionally signed run of digits
+fn parse_int(digits: &str) -> Value {
+    Value::from_bigint(digits.parse::<BigInt>().unwrap())
+}
+
+fn string_arg<'a>(name: &str, args: &'a [Value], i: usize) -> Result<&'a str, String> {
+    match &args[i] {
+        Value::Str(s) => Ok(s),
+        other => Err(format!(
+            "{} expects a string for argument {}, got {}",
+            name,
+            i + 1,
+            other.type_name()
+        )),
+    }
+}
+
+fn index_arg(name: &str, args: &[Value], i: usize) -> Result<usize, String> {
+    match &args[i] {
+        Value::Int(n) if *n >= 0 => Ok(*n as usize),
+        Value::Int(n) => Err(format!(
+            "{} expects a non-negative int for argument {}, got {}",
+            name,
+            i + 1,
+            n
+        )),
+        other => Err(format!(
+            "{} expects an int for argument {}, got {}",
+            name,
+            i + 1,
+            other.type_name()
+        )),
+    }
+}
@@ -0,0 +1,108 @@
+// src/callee.rs
+
+use crate::builtins::{self, Builtin};
+use crate::native::Signature;
+use crate::parser::{Function, Program};
+
+/// What a call by name refers to
+#[derive(Clone, Copy)]
+pub enum Callee<'a> {
+    Script(&'a Function),
+    Native(&'a Signature),
+    Builtin(&'static Builtin),
+}
+
+impl<'a> Callee<'a> {
+    /// Resolve a called name. Script functions shadow natives, which shadow builtins of the
+    /// same name; `native` finds the signature of a function the host provides.
+    pub fn lookup(
+        prog: &'a Program,
+        name: &str,
+        native: impl FnOnce(&str) -> Option<&'a Signature>,
+    ) -> Option<Self> {
+        if let Some(func) = prog.functions.get(name) {
+            return Some(Callee::Script(func));
+        }
+        native(name)
+            .map(Callee::Native)
+            .or_else(|| builtins::lookup(name).map(Callee::Builtin))
+    }
+
+    pub fn name(&self) -> &'a str {
+        match self {
+            Callee::Script(func) => &func.name,
+            Callee::Native(sig) => &sig.name,
+            Callee::Builtin(builtin) => builtin.name,
+        }
+    }
+
+    /// What the function does, if it says
+    pub fn description(&self) -> Option<&'a str> {
+        match self {
+            Callee::Script(func) => func.description.as_deref(),
+            Callee::Native(sig) => sig.description.as_deref(),
+            Callee::Builtin(builtin) => Some(builtin.description),
+        }
+    }
+
+    /// Names and declared types of the params
+    pub fn params(&self) -> Vec<(&'a str, Option<&'a str>)> {
+        match self {
+            Callee::Script(func) => func
+                .params
+                .iter()
+                .map(|p| (p.name.as_str(), p.ptype.as_deref()))
+                .collect(),
+            Callee::Native(sig) => sig
+                .params
+                .iter()
+                .map(|p| (p.name.as_str(), p.ptype.as_deref()))
+                .collect(),
+            Callee::Builtin(builtin) => builtin.params.iter().map(|&p| (p, None)).collect(),
+        }
+    }
+
+    /// Declared type of the result
+    pub fn ret_type(&self) -> Option<&'a str> {
+        match self {
+            Callee::Script(func) => func.ret_type.as_deref(),
+            Callee::Native(sig) => sig.ret_type.as_deref(),
+            Callee::Builtin(_) => None,
+        }
+    }
+
+    /// Check the number of arguments of a call, giving the message to report when it is wrong
+    pub fn check_arity(&self, n: usize) -> Result<(), String> {
+        let (ok, expected) = match self {
+            Callee::Script(func) => (func.params.len() == n, func.params.len().to_string()),
+            Callee::Native(sig) => (sig.params.len() == n, sig.params.len().to_string()),
+            Callee::Builtin(builtin) => (builtin.accepts(n), builtin.arity()),
+        };
+        if ok {
+            return Ok(());
+        }
+        Err(format!(
+            "Expected {} args for function '{}', got {}",
+            expected,
+            self.name(),
+            n
+        ))
+    }
+
+    /// `name(a: int, b) -> int`, leaving out types that are not declared
+    pub fn label(&self) -> String {
+        let params: Vec<String> = self
+            .params()
+            .into_iter()
+            .map(|(name, ty)| match ty {
+                Some(ty) => format!("{}: {}", name, ty),
+                None => name.to_string(),
+            })
+            .collect();
+        let mut label = format!("{}({})", self.name(), params.join(", "));
+        if let Some(ty) = self.ret_type() {
+            label.push_str(&format!(" -> {}", ty));
+        }
+        label
+    }
+}
@@ -0,0 +1,315 @@
+// src/codes.rs
+
+use serde::{Serialize, Serializer};
+use std::fmt;
+
+/// Stable identifier for a kind of problem, shown as `XPL0001` and so on.
+/// Codes are never renumbered or reused, so tools can group problems by them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Code {
+    UndefinedVariable,
+    UndefinedFunction,
+    ArgumentCount,
+    UnknownElement,
+    MissingPart,
+    InvalidExpression,
+    TypeMismatch,
+    UnknownType,
+    Redeclared,
+    UsedBeforeDeclaration,
+    LoopControlOutsideLoop,
+    InvalidLoop,
+    DivisionByZero,
+    BuiltinFailed,
+    MissingMain,
+    Io,
+    Xml,
+    MaybeUnassigned,
+    Unreachable,
+    UnusedVariable,
+    UnusedParameter,
+    NativeFailed,
+    LimitExceeded,
+    Overflow,
+    InvalidAttribute,
+    TooDeep,
+}
+
+/// Registry entry describing a code
+pub struct Explanation {
+    pub code: Code,
+    pub id: &'static str,
+    /// Short lower-case summary
+    pub title: &'static str,
+    pub text: &'static str,
+}
+
+pub const CODES: &[Explanation] = &[
+    Explanation {
+        code: Code::UndefinedVariable,
+        id: "XPL0001",
+        title: "undefined variable",
+        text: "A variable is read, but no parameter, <assign> or loop counter in the function \
+               gives it a value, and the application defines no global of that name. Other \
+               variables are local to the function they are assigned in; pass values to other \
+               functions as parameters.",
+    },
+    Explanation {
+        code: Code::UndefinedFunction,
+        id: "XPL0002",
+        title: "undefined function",
+        text: "A call names a function that is not defined in the program or its included \
+               libraries, is not a builtin and is not provided by the application running the \
+               script. Check the spelling and that the library defining it is \
+               listed in the include attribute of <program>.",
+    },
+    Explanation {
+        code: Code::ArgumentCount,
+        id: "XPL0003",
+        title: "wrong number of arguments",
+        text: "A call passes a different number of <param> arguments than the function declares. \
+               Script functions and functions provided by the application take exactly as many \
+               arguments as they declare; builtins may have optional trailing parameters.",
+    },
+    Explanation {
+        code: Code::UnknownElement,
+        id: "XPL0004",
+        title: "unknown element",
+        text: "An element is not a statement (such as <print>, <assign> or <if>) where a \
+               statement is expected, or not an operator (such as <call>, <eq> or <and>) \
+               inside an expression. This is often a typo or an element placed in the wrong \
+               block, like <then> directly inside <body>.",
+    },
+    Explanation {
+        code: Code::MissingPart,
+        id: "XPL0005",
+        title: "missing element or attribute",
+        text: "A required part of a construct is absent, for example <then> or <else> in an \
+               <if>, <condition> or <body> in a <while>, the name of a <var>, or the from and \
+               to attributes of a <for>.",
+    },
+    Explanation {
+        code: Code::InvalidExpression,
+        id: "XPL0006",
+        title: "invalid expression",
+        text: "The text of an expression could not be parsed: an operator is missing an operand, \
+               a parenthesis or string is not closed, a number is out of range, or a `{` \
+               placeholder in a printed string has no closing `}`.",
+    },
+    Explanation {
+        code: Code::TypeMismatch,
+        id: "XPL0007",
+        title: "type mismatch",
+        text: "A value has a type that cannot be used where it appears: assigning a string to a \
+               variable declared as int, returning the wrong type, passing an argument that does \
+               not match its param type, or applying an operator such as `-` to a string. Loop \
+               counts and for-loop bounds must be ints.",
+    },
+    Explanation {
+        code: Code::UnknownType,
+        id: "XPL0008",
+        title: "unknown type",
+        text: "A type attribute names a type that does not exist. The types are int, float, \
+               decimal, string, bool, list and any.",
+    },
+    Explanation {
+        code: Code::Redeclared,
+        id: "XPL0009",
+        title: "variable declared twice",
+        text: "A <var> declares a name that is already declared or is a parameter of the same \
+               function. Remove the second declaration or pick another name.",
+    },
+    Explanation {
+        code: Code::UsedBeforeDeclaration,
+        id: "XPL0010",
+        title: "variable used before its declaration",
+        text: "A variable is used above the <var> that declares it. Move the declaration before \
+               the first use so its type applies everywhere.",
+    },
+    Explanation {
+        code: Code::LoopControlOutsideLoop,
+        id: "XPL0011",
+        title: "break or continue outside of a loop",
+        text: "<break/> and <continue/> only make sense inside <loop>, <for> or <while>. To leave \
+               a function early, use <return> instead.",
+    },
+    Explanation {
+        code: Code::InvalidLoop,
+        id: "XPL0012",
+        title: "invalid loop bounds",
+        text: "A loop cannot run as written: the count of a <loop> is negative, the step of a \
+               <for> is zero, or inclusive is something other than true or false.",
+    },
+    Explanation {
+        code: Code::DivisionByZero,
+        id: "XPL0013",
+        title: "division by zero",
+        text: "An int or decimal was divided by zero with `/` or `%`. Check the divisor first, \
+               or convert to float, where division by zero gives infinity or NaN.",
+    },
+    Explanation {
+        code: Code::BuiltinFailed,
+        id: "XPL0014",
+        title: "builtin call failed",
+        text: "A builtin rejected its arguments at run time, for example `get` with an index past \
+               the end of the list or `upper` given a number. The message says which argument \
+               was wrong.",
+    },
+    Explanation {
+        code: Code::MissingMain,
+        id: "XPL0015",
+        title: "no main function",
+        text: "The program was run but defines no function named main, which is where execution \
+               starts. Libraries meant to be included do not need one.",
+    },
+    Explanation {
+        code: Code::Io,
+        id: "XPL0016",
+        title: "file cannot be read",
+        text: "The script or one of the libraries in its include attribute does not exist or \
+               cannot be read. Includes are looked up next to the script first, then relative to \
+               the working directory.",
+    },
+    Explanation {
+        code: Code::Xml,
+        id: "XPL0017",
+        title: "malformed XML",
+        text: "The file is not well-formed XML, so nothing in it could be checked. Common causes \
+               are unclosed or mismatched tags and a bare `<` or `&` in text; write them as \
+               `&lt;` and `&amp;`.",
+    },
+    Explanation {
+        code: Code::MaybeUnassigned,
+        id: "XPL0018",
+        title: "variable may be used before it is assigned",
+        text: "A variable is read above the first statement that assigns it. This is fine when a \
+               loop assigns it on an earlier iteration, but otherwise the read fails at run time.",
+    },
+    Explanation {
+        code: Code::Unreachable,
+        id: "XPL0019",
+        title: "unreachable code",
+        text: "A statement follows a <return>, <break/> or <continue/> in the same block, or an \
+               <if> whose branches all leave, so it can never run.",
+    },
+    Explanation {
+        code: Code::UnusedVariable,
+        id: "XPL0020",
+        title: "unused variable",
+        text: "A variable is declared or assigned but never read. Remove it, or start its name \
+               with `_` to show it is unused on purpose.",
+    },
+    Explanation {
+        code: Code::UnusedParameter,
+        id: "XPL0021",
+        title: "unused parameter",
+        text: "A function never reads one of its parameters. Remove it from the function and its \
+               callers, or start its name with `_` to show it is unused on purpose.",
+    },
+    Explanation {
+        code: Code::NativeFailed,
+        id: "XPL0022",
+        title: "native call failed",
+        text: "A function provided by the application running the script returned an error. \
+               The message comes from the application; see its documentation for what the \
+               function expects.",
+    },
+    Explanation {
+        code: Code::LimitExceeded,
+        id: "XPL0023",
+        title: "execution limit exceeded",
+        text: "The script ran longer, recursed deeper, looped more or printed more than the \
+               application running it allows, and was stopped. Deeply nested calls, statements \
+               and expressions are stopped even when the application sets no limits; look for \
+               recursion without a base case or a loop whose condition never becomes false.",
+    },
+    Explanation {
+        code: Code::Overflow,
+        id: "XPL0024",
+        title: "integer overflow",
+        text: "An integer operation gave a result outside the 64-bit range, such as adding one \
+               to the largest int or negating the smallest, or an integer literal does not fit \
+               in 64 bits. Give <program> an arithmetic attribute of bigint for exact results \
+               of any size, or of wrapping or saturating when overflowing on purpose.",
+    },
+    Explanation {
+        code: Code::InvalidAttribute,
+        id: "XPL0025",
+        title: "invalid attribute value",
+        text: "An attribute has a value it does not accept, such as an arithmetic attribute on \
+               <program> other than checked, wrapping, saturating or bigint.",
+    },
+    Explanation {
+        code: Code::TooDeep,
+        id: "XPL0026",
+        title: "nested too deeply",
+        text: "Statements or expressions are nested inside one another more deeply than a \
+               script may nest them, such as hundreds of <if> elements each inside the last or \
+               an expression with hundreds of levels of parentheses. Move the inner part into \
+               a function of its own, or keep an intermediate result in a variable.",
+    },
+];
+
+/// Where the registry is published, one section per code
+const DOCS_URL: &str = "https://github.com/omdxp/xpl/blob/main/docs/errors.md";
+
+impl Explanation {
+    /// Link to this code's section of the published registry
+    pub fn url(&self) -> String {
+        format!(
+            "{}#{}-{}",
+            DOCS_URL,
+            self.id.to_lowercase(),
+            self.title.replace(' ', "-")
+        )
+    }
+}
+
+impl Code {
+    pub fn explanation(self) -> &'static Explanation {
+        CODES
+            .iter()
+            .find(|e| e.code == self)
+            .expect("every code is registered")
+    }
+
+    /// The `XPL0001`-style identifier
+    pub fn id(self) -> &'static str {
+        self.explanation().id
+    }
+}
+
+impl fmt::Display for Code {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.id())
+    }
+}
+
+// serialized as its identifier, which stays stable when variants are renamed
+impl Serialize for Code {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.id())
+    }
+}
+
+/// Look up a code by its identifier, ignoring case
+pub fn lookup(id: &str) -> Option<&'static Explanation> {
+    CODES.iter().find(|e| e.id.eq_ignore_ascii_case(id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registry_is_complete_and_documented() {
+        let docs = std::fs::read_to_string("docs/errors.md").unwrap();
+        for (i, entry) in CODES.iter().enumerate() {
+            // ids are sequential, so a new code goes at the end
+            assert_eq!(entry.id, format!("XPL{:04}", i + 1));
+            assert_eq!(lookup(&entry.id.to_lowercase()).unwrap().code, entry.code);
+            let heading = format!("## {}: {}", entry.id, entry.title);
+            assert!(docs.contains(&heading), "docs/errors.md lacks {}", heading);
+        }
+    }
+}
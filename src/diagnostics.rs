@@ -0,0 +1,453 @@
+// src/diagnostics.rs
+
+use crate::codes::Code;
+use crate::error::XplError;
+use crate::render::{self, Mark, Painter};
+use crate::span::{Pos, Span};
+use ansi_term::Colour::{Blue, Cyan, Red, Yellow};
+use ansi_term::Style;
+use serde::Serialize;
+use std::fmt;
+
+/// How serious a diagnostic is; only errors stop a program from running
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Error,
+    Warning,
+    /// Extra context for the diagnostic before it
+    Note,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+            Severity::Note => "note",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// One problem found in a file
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// Notes share the code of the diagnostic they add context to
+    pub code: Code,
+    #[serde(rename = "message")]
+    pub msg: String,
+    pub file: String,
+    pub span: Span,
+    /// Related places, possibly in other files, shown under the primary span
+    #[serde(rename = "related")]
+    pub labels: Vec<Label>,
+    /// Extra context printed after the source
+    pub notes: Vec<String>,
+    /// A known name close to the one that was not found
+    pub suggestion: Option<String>,
+}
+
+/// A secondary span with a short message, such as the definition a call refers to
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Label {
+    pub file: String,
+    pub span: Span,
+    #[serde(rename = "message")]
+    pub msg: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}: {}[{}]: {}",
+            self.file, self.span.start, self.severity, self.code, self.msg
+        )
+    }
+}
+
+impl Diagnostic {
+    pub fn new(severity: Severity, code: Code, file: &str, span: Span, msg: String) -> Self {
+        Diagnostic {
+            severity,
+            code,
+            msg,
+            file: file.to_string(),
+            span,
+            labels: Vec::new(),
+            notes: Vec::new(),
+            suggestion: None,
+        }
+    }
+
+    /// Point at a related place with a short message
+    pub fn label(&mut self, file: &str, span: Span, msg: String) -> &mut Self {
+        self.labels.push(Label {
+            file: file.to_string(),
+            span,
+            msg,
+        });
+        self
+    }
+
+    pub fn note(&mut self, msg: String) -> &mut Self {
+        self.notes.push(msg);
+        self
+    }
+
+    /// Suggest the closest of `candidates` to `name`, if any is close enough
+    pub fn suggest<'a>(
+        &mut self,
+        name: &str,
+        candidates: impl IntoIterator<Item = &'a str>,
+    ) -> &mut Self {
+        self.suggestion = did_you_mean(name, candidates).map(str::to_string);
+        self
+    }
+
+    /// Print the diagnostic with its source, colored when stderr is a terminal
+    pub fn pretty_print(&self) {
+        eprint!("{}", self.render(render::use_color()));
+    }
+
+    /// The diagnostic as printed by `pretty_print`: header, primary and secondary spans
+    /// with their source lines, then notes and the suggestion
+    pub fn render(&self, color: bool) -> String {
+        let p = Painter { color };
+        let style = match self.severity {
+            Severity::Error => Red.bold(),
+            Severity::Warning => Yellow.bold(),
+            Severity::Note => Cyan.bold(),
+        };
+        let gutter = Blue.bold();
+        let width = self
+            .labels
+            .iter()
+            .map(|l| l.span.start.line)
+            .chain([self.span.start.line])
+            .map(render::gutter_width)
+            .max()
+            .unwrap_or(1);
+        let pad = " ".repeat(width);
+        let mut out = format!(
+            "{}: {}\n",
+            p.paint(style, &format!("{}[{}]", self.severity, self.code)),
+            p.paint(Style::new().bold(), &self.msg)
+        );
+        out.push_str(&format!(
+            "{}{} {}\n",
+            pad,
+            p.paint(gutter, "-->"),
+            location(&self.file, self.span)
+        ));
+        let primary = Mark {
+            span: self.span,
+            underline: '^',
+            style,
+            msg: "",
+        };
+        render::snippet(&mut out, &self.file, &primary, width, p);
+        for label in &self.labels {
+            // places in other files get their own location line
+            if label.file != self.file {
+                out.push_str(&format!(
+                    "{}{} {}\n",
+                    pad,
+                    p.paint(gutter, ":::"),
+                    location(&label.file, label.span)
+                ));
+            }
+            let mark = Mark {
+                span: label.span,
+                underline: '-',
+                style: gutter,
+                msg: &label.msg,
+            };
+            render::snippet(&mut out, &label.file, &mark, width, p);
+        }
+        let bold = Style::new().bold();
+        for note in &self.notes {
+            out.push_str(&format!("{} = {}: {}\n", pad, p.paint(bold, "note"), note));
+        }
+        if let Some(name) = &self.suggestion {
+            out.push_str(&format!(
+                "{} = {}: did you mean `{}`?\n",
+                pad,
+                p.paint(bold, "help"),
+                name
+            ));
+        }
+        out
+    }
+}
+
+/// `file:line:col`, or just the file when the position is unknown
+fn location(file: &str, span: Span) -> String {
+    if span.start.line == 0 {
+        file.to_string()
+    } else {
+        format!("{}:{}", file, span.start)
+    }
+}
+
+impl From<&XplError> for Diagnostic {
+    fn from(err: &XplError) -> Self {
+        let (msg, file, span) = match err {
+            XplError::Semantic {
+                msg,
+                file,
+                line,
+                col,
+                ..
+            } => {
+                let at = Pos::new(*line, *col);
+                (msg.clone(), file, Span::new(at, at))
+            }
+            XplError::LimitExceeded {
+                limit,
+                file,
+                line,
+                col,
+            } => {
+                let at = Pos::new(*line, *col);
+                (limit.to_string(), file, Span::new(at, at))
+            }
+            XplError::Io {
+                source,
+                file,
+                included_from: Some(site),
+            } => {
+                // point at the include rather than at a file that cannot be shown
+                let at = Pos::new(site.line, site.col);
+                let msg = format!("Cannot read included file {}: {}", file, source);
+                (msg, &site.file, Span::new(at, at))
+            }
+            XplError::Io { source, file, .. } => (source.to_string(), file, Span::default()),
+            XplError::Xml {
+                source,
+                file,
+                line,
+                col,
+            } => {
+                let at = Pos::new(*line, *col);
+                (source.msg().to_string(), file, Span::new(at, at))
+            }
+        };
+        Diagnostic::new(Severity::Error, err.code(), file, span, msg)
+    }
+}
+
+impl From<XplError> for Diagnostic {
+    fn from(err: XplError) -> Self {
+        Diagnostic::from(&err)
+    }
+}
+
+impl From<Diagnostic> for XplError {
+    fn from(diag: Diagnostic) -> Self {
+        XplError::Semantic {
+            code: diag.code,
+            msg: diag.msg,
+            file: diag.file,
+            line: diag.span.start.line,
+            col: diag.span.start.col,
+        }
+    }
+}
+
+/// Problems collected while checking a program, in the order they were found
+#[derive(Debug, Default)]
+pub struct Diagnostics {
+    items: Vec<Diagnostic>,
+}
+
+impl Diagnostics {
+    pub fn new() -> Self {
+        Diagnostics::default()
+    }
+
+    pub fn push(&mut self, diag: Diagnostic) {
+        self.items.push(diag);
+    }
+
+    /// Add a diagnostic, returning it so labels and notes can be attached
+    fn add(
+        &mut self,
+        severity: Severity,
+        file: &str,
+        span: Span,
+        code: Code,
+        msg: String,
+    ) -> &mut Diagnostic {
+        self.push(Diagnostic::new(severity, code, file, span, msg));
+        self.items.last_mut().unwrap()
+    }
+
+    pub fn error(&mut self, file: &str, span: Span, code: Code, msg: String) -> &mut Diagnostic {
+        self.add(Severity::Error, file, span, code, msg)
+    }
+
+    pub fn warning(&mut self, file: &str, span: Span, code: Code, msg: String) -> &mut Diagnostic {
+        self.add(Severity::Warning, file, span, code, msg)
+    }
+
+    pub fn note(&mut self, file: &str, span: Span, code: Code, msg: String) -> &mut Diagnostic {
+        self.add(Severity::Note, file, span, code, msg)
+    }
+
+    pub fn extend(&mut self, other: Diagnostics) {
+        self.items.extend(other.items);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, Diagnostic> {
+        self.items.iter()
+    }
+
+    /// Number of diagnostics with the given severity
+    pub fn count(&self, severity: Severity) -> usize {
+        self.items.iter().filter(|d| d.severity == severity).count()
+    }
+
+    pub fn has_errors(&self) -> bool {
+        self.first_error().is_some()
+    }
+
+    pub fn first_error(&self) -> Option<&Diagnostic> {
+        self.items.iter().find(|d| d.severity == Severity::Error)
+    }
+
+    /// Print every diagnostic followed by a summary line
+    pub fn pretty_print(&self) {
+        for diag in &self.items {
+            diag.pretty_print();
+        }
+        let (errors, warnings) = (self.count(Severity::Error), self.count(Severity::Warning));
+        if errors + warnings > 0 {
+            let plural = |n: usize| if n == 1 { "" } else { "s" };
+            eprintln!(
+                "{} error{}, {} warning{}",
+                errors,
+                plural(errors),
+                warnings,
+                plural(warnings)
+            );
+        }
+    }
+}
+
+impl IntoIterator for Diagnostics {
+    type Item = Diagnostic;
+    type IntoIter = std::vec::IntoIter<Diagnostic>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.items.into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a Diagnostics {
+    type Item = &'a Diagnostic;
+    type IntoIter = std::slice::Iter<'a, Diagnostic>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.items.iter()
+    }
+}
+
+/// The candidate closest to `name` by edit distance, if it is close enough to be a likely typo
+pub fn did_you_mean<'a>(
+    name: &str,
+    candidates: impl IntoIterator<Item = &'a str>,
+) -> Option<&'a str> {
+    // allow roughly one edit per three characters
+    let max = name.chars().count().max(3) / 3;
+    candidates
+        .into_iter()
+        .filter(|c| *c != name)
+        .map(|c| (edit_distance(name, c), c))
+        .filter(|&(d, _)| d <= max)
+        .min()
+        .map(|(_, c)| c)
+}
+
+/// Levenshtein distance between two strings, counted in characters
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut cur = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let cost = usize::from(ca != *cb);
+            cur.push((prev[j] + cost).min(prev[j + 1] + 1).min(cur[j] + 1));
+        }
+        prev = cur;
+    }
+    prev[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn suggests_close_names_only() {
+        let names = ["subtract", "add", "total", "multiply"];
+        assert_eq!(did_you_mean("substract", names), Some("subtract"));
+        assert_eq!(did_you_mean("totl", names), Some("total"));
+        assert_eq!(did_you_mean("ad", names), Some("add"));
+        assert_eq!(did_you_mean("divide", names), None);
+        assert_eq!(did_you_mean("add", names), None);
+    }
+
+    #[test]
+    fn renders_labels_notes_and_suggestions() {
+        let path = std::env::temp_dir().join("render.xpl");
+        let lib = std::env::temp_dir().join("render_lib.xpl");
+        std::fs::write(&path, "<program>\n\t<print>ad(1)</print>\n</program>").unwrap();
+        std::fs::write(&lib, "<lib>\n  <function name=\"add\"/>\n</lib>").unwrap();
+        let (path, lib) = (path.to_str().unwrap(), lib.to_str().unwrap());
+        let at = |line, col, len| Span::new(Pos::new(line, col), Pos::new(line, col + len));
+        let mut diag = Diagnostic::new(
+            Severity::Error,
+            Code::UndefinedFunction,
+            path,
+            at(2, 9, 5),
+            "Undefined function ad".to_string(),
+        );
+        diag.label(lib, at(2, 3, 22), "similar function".to_string())
+            .note("functions must be defined or included".to_string())
+            .suggest("ad", ["add"]);
+        let expected = format!(
+            "error[XPL0002]: Undefined function ad\n \
+             --> {path}:2:9\n  \
+             |\n\
+             2 | \t<print>ad(1)</print>\n  \
+             | \t       ^^^^^\n \
+             ::: {lib}:2:3\n  \
+             |\n\
+             2 |   <function name=\"add\"/>\n  \
+             |   ---------------------- similar function\n  \
+             = note: functions must be defined or included\n  \
+             = help: did you mean `add`?\n"
+        );
+        assert_eq!(diag.render(false), expected);
+        // an unknown position names the file only
+        let io = Diagnostic::new(
+            Severity::Error,
+            Code::Io,
+            "gone.xpl",
+            Span::default(),
+            "missing".into(),
+        );
+        assert_eq!(io.render(false), "error[XPL0016]: missing\n --> gone.xpl\n");
+    }
+}
@@ -0,0 +1,71 @@
+// src/doc.rs
+
+use crate::callee::Callee;
+use crate::parser::{Function, Program};
+
+/// Markdown reference for the functions defined in `file`, in source order,
+/// built from the `<description>` elements of the program, functions and params
+pub fn render_markdown(prog: &Program, file: &str) -> String {
+    let title = std::path::Path::new(file)
+        .file_stem()
+        .map_or(file.into(), |s| s.to_string_lossy());
+    let mut out = format!("# {}\n", title);
+    if let Some(desc) = &prog.description {
+        out.push_str(&format!("\n{}\n", unindent(desc)));
+    }
+    // included libraries document themselves
+    let mut funcs: Vec<&Function> = prog.functions.values().filter(|f| f.file == file).collect();
+    funcs.sort_by_key(|f| f.span.start);
+    if !funcs.is_empty() {
+        out.push_str("\n## Functions\n");
+    }
+    for func in funcs {
+        out.push_str(&format!("\n### `{}`\n", Callee::Script(func).label()));
+        if let Some(desc) = &func.description {
+            out.push_str(&format!("\n{}\n", unindent(desc)));
+        }
+        if !func.params.is_empty() {
+            out.push('\n');
+        }
+        for p in &func.params {
+            out.push_str(&format!("- `{}`", p.name));
+            if let Some(ty) = &p.ptype {
+                out.push_str(&format!(" ({})", ty));
+            }
+            if let Some(desc) = &p.description {
+                out.push_str(&format!(": {}", unindent(desc)));
+            }
+            out.push('\n');
+        }
+    }
+    out
+}
+
+/// Description text with the indentation of the surrounding XML removed from each line
+fn unindent(text: &str) -> String {
+    text.lines()
+        .map(str::trim)
+        .collect::<Vec<_>>()
+        .join("\n")
+        .trim()
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_file;
+
+    #[test]
+    fn documents_functions_in_source_order() {
+        let prog = parse_file("examples/math.xpl").unwrap();
+        let doc = render_markdown(&prog, "examples/math.xpl");
+        assert!(doc.starts_with(
+            "# math\n\nThis library provides basic mathematical operations.\n\n## Functions\n\n\
+             ### `add(a: int, b: int) -> int`\n\nThis function adds two integers.\n"
+        ));
+        let add = doc.find("### `add(").unwrap();
+        let subtract = doc.find("### `subtract(").unwrap();
+        assert!(add < subtract);
+    }
+}
@@ -0,0 +1,378 @@
+// src/dom.rs
+
+use crate::span::{Pos, Span};
+use std::cell::OnceCell;
+use std::collections::HashMap;
+use xml::common::{Position, TextPosition};
+use xml::reader::{ParserConfig, XmlEvent};
+
+pub use xml::reader::Error;
+
+/// An element of the document, remembering where it was written
+#[derive(Debug, Clone)]
+pub struct Element {
+    pub name: String,
+    pub attributes: HashMap<String, Text>,
+    pub children: Vec<XMLNode>,
+    /// From the `<` of the start tag to just past the `>` of the end tag
+    pub span: Span,
+}
+
+#[derive(Debug, Clone)]
+pub enum XMLNode {
+    Element(Element),
+    Text(Text),
+}
+
+/// Character data of element content or of an attribute value
+#[derive(Debug, Clone)]
+pub struct Text {
+    /// Content with entities and line endings decoded
+    pub text: String,
+    /// Content exactly as written in the file
+    raw: String,
+    /// Position of the first raw character
+    pub start: Pos,
+    /// Whether `raw` may contain entity references (not inside CDATA)
+    entities: bool,
+    /// File position of each character of `text` by byte offset, built on first use so
+    /// spans of many tokens cost one pass over the text
+    positions: OnceCell<Vec<(usize, Pos)>>,
+}
+
+impl Text {
+    /// Text that was not read from a document verbatim, positioned from `start` onwards
+    pub fn new(text: String, start: Pos) -> Self {
+        Text {
+            raw: text.clone(),
+            text,
+            start,
+            entities: false,
+            positions: OnceCell::new(),
+        }
+    }
+
+    /// File position of a byte offset into the decoded text
+    pub fn pos_at(&self, offset: usize) -> Pos {
+        let positions = self.positions.get_or_init(|| self.char_positions());
+        match positions.binary_search_by_key(&offset, |&(o, _)| o) {
+            Ok(i) => positions[i].1,
+            // inside a character: where that character starts
+            Err(i) => positions[i - 1].1,
+        }
+    }
+
+    /// Position of every character of the decoded text, and of its end
+    fn char_positions(&self) -> Vec<(usize, Pos)> {
+        let mut positions = Vec::with_capacity(self.text.len() + 1);
+        let mut pos = self.start;
+        let mut raw = self.raw.chars().peekable();
+        for (offset, c) in self.text.char_indices() {
+            positions.push((offset, pos));
+            let Some(r) = raw.next() else {
+                continue;
+            };
+            advance(&mut pos, r);
+            if r == '&' && self.entities {
+                // one decoded character stands for the whole `&...;` reference
+                for r in raw.by_ref() {
+                    advance(&mut pos, r);
+                    if r == ';' {
+                        break;
+                    }
+                }
+            } else if r == '\r' && c == '\n' && raw.peek() == Some(&'\n') {
+                advance(&mut pos, raw.next().unwrap());
+            }
+        }
+        positions.push((self.text.len(), pos));
+        positions
+    }
+
+    /// Span of `len` bytes of decoded text starting at `offset`
+    pub fn span_at(&self, offset: usize, len: usize) -> Span {
+        Span::new(self.pos_at(offset), self.pos_at(offset + len))
+    }
+}
+
+fn advance(pos: &mut Pos, c: char) {
+    match c {
+        '\n' => {
+            pos.line += 1;
+            pos.col = 1;
+        }
+        '\r' => {}
+        _ => pos.col += 1,
+    }
+}
+
+impl Element {
+    /// First child element called `name`
+    pub fn get_child(&self, name: &str) -> Option<&Element> {
+        self.children.iter().find_map(|n| match n {
+            XMLNode::Element(e) if e.name == name => Some(e),
+            _ => None,
+        })
+    }
+
+    /// Decoded value of an attribute
+    pub fn attr(&self, name: &str) -> Option<&str> {
+        self.attributes.get(name).map(|t| t.text.as_str())
+    }
+
+    /// All text directly inside the element, joined
+    pub fn text(&self) -> String {
+        self.children
+            .iter()
+            .filter_map(|n| match n {
+                XMLNode::Text(t) => Some(t.text.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+/// Byte offsets of line starts, to convert between reader positions and offsets
+struct Lines<'a> {
+    src: &'a str,
+    starts: Vec<usize>,
+    /// The last position converted, as a byte offset with its 0-based row and column.
+    /// Positions mostly come in file order, so counting on from here keeps each conversion
+    /// to the characters in between instead of the whole line.
+    cursor: (usize, usize, usize),
+}
+
+impl<'a> Lines<'a> {
+    fn new(src: &'a str) -> Self {
+        let starts = std::iter::once(0)
+            .chain(src.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        Lines {
+            src,
+            starts,
+            cursor: (0, 0, 0),
+        }
+    }
+
+    /// Byte offset and column to count on from for a position at `column` of `row`
+    fn counting_from(&self, row: usize, start: usize, column: usize) -> (usize, usize) {
+        match self.cursor {
+            (offset, r, c) if r == row && offset >= start && c <= column => (offset, c),
+            _ => (start, 0),
+        }
+    }
+
+    /// Byte offset of a 0-based reader position (columns count characters)
+    fn offset(&mut self, at: TextPosition) -> usize {
+        let (row, column) = (at.row as usize, at.column as usize);
+        let Some(&start) = self.starts.get(row) else {
+            return self.src.len();
+        };
+        let (from, col) = self.counting_from(row, start, column);
+        let Some((i, _)) = self.src[from..].char_indices().nth(column - col) else {
+            return self.src.len();
+        };
+        self.cursor = (from + i, row, column);
+        from + i
+    }
+
+    fn pos(&mut self, offset: usize) -> Pos {
+        let row = self.starts.partition_point(|&s| s <= offset) - 1;
+        let start = self.starts[row];
+        let (from, col) = match self.counting_from(row, start, usize::MAX) {
+            (from, col) if from <= offset => (from, col),
+            _ => (start, 0),
+        };
+        let column = col + self.src[from..offset].chars().count();
+        self.cursor = (offset, row, column);
+        Pos::new(row + 1, column + 1)
+    }
+}
+
+/// Attribute values of a start tag beginning at `start`, with where the tag ends
+fn scan_tag(src: &str, start: usize) -> (HashMap<&str, (usize, &str)>, usize) {
+    let bytes = src.as_bytes();
+    let at = |i: usize| bytes.get(i).copied().unwrap_or(b'>');
+    let mut attrs = HashMap::new();
+    let mut i = start + 1;
+    while !matches!(at(i), b'/' | b'>') && !at(i).is_ascii_whitespace() {
+        i += 1;
+    }
+    loop {
+        while at(i).is_ascii_whitespace() || at(i) == b'/' {
+            i += 1;
+        }
+        if at(i) == b'>' {
+            return (attrs, (i + 1).min(src.len()));
+        }
+        let name_start = i;
+        while at(i) != b'=' && !at(i).is_ascii_whitespace() && at(i) != b'>' {
+            i += 1;
+        }
+        let name = &src[name_start..i];
+        while at(i) == b'=' || at(i).is_ascii_whitespace() {
+            i += 1;
+        }
+        let quote = at(i);
+        if quote != b'"' && quote != b'\'' {
+            continue;
+        }
+        let value_start = i + 1;
+        i = value_start;
+        while i < bytes.len() && bytes[i] != quote {
+            i += 1;
+        }
+        attrs.insert(name, (value_start, &src[value_start..i.min(src.len())]));
+        i += 1;
+    }
+}
+
+/// 1-based position of a parse error
+pub fn error_pos(err: &Error) -> Pos {
+    let pos = err.position();
+    Pos::new(pos.row as usize + 1, pos.column as usize + 1)
+}
+
+/// Parse a document into its root element
+pub fn parse(src: &str) -> Result<Element, Error> {
+    let mut lines = Lines::new(src);
+    let mut reader = ParserConfig::new()
+        .trim_whitespace(false)
+        .whitespace_to_characters(true)
+        // comments and CDATA end a text run, so every run maps back onto the file
+        .ignore_comments(false)
+        .coalesce_characters(false)
+        .create_reader(src.as_bytes());
+    // open elements with the offset just past their start tag
+    let mut open: Vec<(Element, usize)> = Vec::new();
+    let mut root = None;
+    loop {
+        let event = reader.next()?;
+        let offset = lines.offset(reader.position());
+        match event {
+            XmlEvent::StartElement {
+                name, attributes, ..
+            } => {
+                // the reader may report the root element at the end of its start tag
+                let offset = src[..=offset.min(src.len() - 1)]
+                    .rfind('<')
+                    .unwrap_or(offset);
+                let (raw_attrs, tag_end) = scan_tag(src, offset);
+                let attributes = attributes
+                    .into_iter()
+                    .map(|a| {
+                        let key = a.name.borrow().to_repr();
+                        let text = match raw_attrs.get(key.as_str()) {
+                            Some(&(start, raw)) => Text {
+                                text: a.value,
+                                raw: raw.to_string(),
+                                start: lines.pos(start),
+                                entities: true,
+                                positions: OnceCell::new(),
+                            },
+                            None => Text::new(a.value, lines.pos(offset)),
+                        };
+                        (key, text)
+                    })
+                    .collect();
+                let elem = Element {
+                    name: name.local_name,
+                    attributes,
+                    children: Vec::new(),
+                    span: Span::new(lines.pos(offset), lines.pos(tag_end)),
+                };
+                open.push((elem, tag_end));
+            }
+            XmlEvent::EndElement { .. } => {
+                let (mut elem, tag_end) = open.pop().expect("reader checks nesting");
+                // a self-closing element ends with its start tag
+                let end = if src[offset..].starts_with("</") {
+                    src[offset..]
+                        .find('>')
+                        .map_or(src.len(), |i| offset + i + 1)
+                } else {
+                    tag_end
+                };
+                elem.span.end = lines.pos(end);
+                match open.last_mut() {
+                    Some((parent, _)) => parent.children.push(XMLNode::Element(elem)),
+                    None => root = Some(elem),
+                }
+            }
+            XmlEvent::Characters(text) => {
+                if let Some((parent, _)) = open.last_mut() {
+                    let len = src[offset..].find('<').unwrap_or(src.len() - offset);
+                    parent.children.push(XMLNode::Text(Text {
+                        text,
+                        raw: src[offset..offset + len].to_string(),
+                        start: lines.pos(offset),
+                        entities: true,
+                        positions: OnceCell::new(),
+                    }));
+                }
+            }
+            XmlEvent::CData(text) => {
+                if let Some((parent, _)) = open.last_mut() {
+                    let start = lines.pos(offset + "<![CDATA[".len());
+                    parent.children.push(XMLNode::Text(Text::new(text, start)));
+                }
+            }
+            XmlEvent::EndDocument => break,
+            _ => {}
+        }
+    }
+    Ok(root.expect("reader requires a root element"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn positions_of_elements_attributes_and_text() {
+        let src = "<?xml version=\"1.0\"?>\r\n<a x=\"1\">\r\n  h\u{e9} &lt; b<!-- c -->d\n  <b y='&amp;2'/><c>t</c>\n</a>";
+        let root = parse(src).unwrap();
+        assert_eq!(root.span, Span::new(Pos::new(2, 1), Pos::new(5, 5)));
+        let XMLNode::Text(t) = &root.children[0] else {
+            panic!("expected text");
+        };
+        assert_eq!(t.text, "\r\n  h\u{e9} < b");
+        // the decoded '<' sits where `&lt;` was written, 'b' after it
+        let lt = t.text.find('<').unwrap();
+        assert_eq!(t.pos_at(lt), Pos::new(3, 6));
+        assert_eq!(t.pos_at(lt + 2), Pos::new(3, 11));
+        let b = root.get_child("b").unwrap();
+        assert_eq!(b.span, Span::new(Pos::new(4, 3), Pos::new(4, 18)));
+        let y = &b.attributes["y"];
+        assert_eq!((y.text.as_str(), y.pos_at(1)), ("&2", Pos::new(4, 14)));
+        let c = root.get_child("c").unwrap();
+        assert_eq!(c.span, Span::new(Pos::new(4, 18), Pos::new(4, 26)));
+        assert_eq!(c.text(), "t");
+    }
+
+    #[test]
+    fn positions_along_one_long_line() {
+        let src = format!("<a>{}</a>", "<b x=\"1\">\u{e9}</b>".repeat(1000));
+        let root = parse(&src).unwrap();
+        let XMLNode::Element(last) = root.children.last().unwrap() else {
+            panic!("expected element");
+        };
+        // every <b> takes 14 characters, whatever the bytes of its text
+        let start = 4 + 999 * 14;
+        assert_eq!(
+            last.span,
+            Span::new(Pos::new(1, start), Pos::new(1, start + 14))
+        );
+        assert_eq!(last.attributes["x"].pos_at(0), Pos::new(1, start + 6));
+    }
+
+    #[test]
+    fn root_and_error_positions() {
+        // without a declaration the reader reports the root at the end of its start tag
+        let root = parse("<a x=\"1\"\n  y=\"p, q\">\n</a>").unwrap();
+        assert_eq!(root.span.start, Pos::new(1, 1));
+        assert_eq!(root.attributes["y"].pos_at(3), Pos::new(2, 9));
+        let err = parse("<a>\n  <b></c>\n</a>").unwrap_err();
+        assert_eq!(error_pos(&err), Pos::new(2, 9));
+    }
+}
@@ -0,0 +1,227 @@
+// src/emit.rs
+
+use crate::codes::{CODES, Code};
+use crate::diagnostics::{Diagnostic, Diagnostics};
+use crate::span::Span;
+use serde_json::{Value, json};
+use std::str::FromStr;
+
+/// How diagnostics are written for the reader on the other end
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ErrorFormat {
+    /// Colored text with source snippets, for people
+    #[default]
+    Human,
+    /// One JSON object per diagnostic, one per line
+    Json,
+    /// A single SARIF 2.1.0 log, for code-review tools
+    Sarif,
+}
+
+impl FromStr for ErrorFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "human" => Ok(ErrorFormat::Human),
+            "json" => Ok(ErrorFormat::Json),
+            "sarif" => Ok(ErrorFormat::Sarif),
+            other => Err(format!(
+                "Unknown error format {}, expected human, json or sarif",
+                other
+            )),
+        }
+    }
+}
+
+/// Serialize each diagnostic as a JSON object on its own line
+pub fn to_json(diags: &Diagnostics) -> String {
+    diags
+        .iter()
+        .map(|d| serde_json::to_string(d).expect("diagnostics always serialize") + "\n")
+        .collect()
+}
+
+/// Build a SARIF log with one run listing every code as a rule
+pub fn to_sarif(diags: &Diagnostics) -> Value {
+    let rules: Vec<Value> = CODES
+        .iter()
+        .map(|e| {
+            json!({
+                "id": e.id,
+                "shortDescription": { "text": e.title },
+                "fullDescription": { "text": e.text },
+                "helpUri": e.url(),
+            })
+        })
+        .collect();
+    let results: Vec<Value> = diags.iter().map(sarif_result).collect();
+    json!({
+        "$schema": "https://json.schemastore.org/sarif-2.1.0.json",
+        "version": "2.1.0",
+        "runs": [{
+            "tool": {
+                "driver": {
+                    "name": "xpl",
+                    "version": env!("CARGO_PKG_VERSION"),
+                    "informationUri": "https://github.com/omdxp/xpl",
+                    "rules": rules,
+                }
+            },
+            "results": results,
+        }]
+    })
+}
+
+fn sarif_result(diag: &Diagnostic) -> Value {
+    // SARIF has no field for extra text, so notes and the suggestion join the message
+    let mut text = diag.msg.clone();
+    for note in &diag.notes {
+        text.push_str(&format!("\nnote: {}", note));
+    }
+    if let Some(name) = &diag.suggestion {
+        text.push_str(&format!("\nhelp: did you mean `{}`?", name));
+    }
+    let related: Vec<Value> = diag
+        .labels
+        .iter()
+        .enumerate()
+        .map(|(i, label)| {
+            let mut loc = sarif_location(&label.file, label.span);
+            loc["id"] = json!(i);
+            loc["message"] = json!({ "text": label.msg });
+            loc
+        })
+        .collect();
+    json!({
+        "ruleId": diag.code.id(),
+        "ruleIndex": rule_index(diag.code),
+        // severities are named like SARIF levels
+        "level": diag.severity.to_string(),
+        "message": { "text": text },
+        "locations": [sarif_location(&diag.file, diag.span)],
+        "relatedLocations": related,
+    })
+}
+
+/// A physical location; the region is left out when the position is unknown
+fn sarif_location(file: &str, span: Span) -> Value {
+    let mut physical = json!({ "artifactLocation": { "uri": file } });
+    if span.start.line > 0 {
+        let end = if span.end > span.start {
+            span.end
+        } else {
+            span.start
+        };
+        physical["region"] = json!({
+            "startLine": span.start.line,
+            "startColumn": span.start.col,
+            "endLine": end.line,
+            "endColumn": end.col,
+        });
+    }
+    json!({ "physicalLocation": physical })
+}
+
+fn rule_index(code: Code) -> usize {
+    CODES
+        .iter()
+        .position(|e| e.code == code)
+        .expect("every code is registered")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::span::Pos;
+
+    fn sample() -> Diagnostics {
+        let mut diags = Diagnostics::new();
+        let span = Span::new(Pos::new(3, 5), Pos::new(3, 8));
+        diags
+            .error(
+                "main.xpl",
+                span,
+                Code::ArgumentCount,
+                "Expected 2 args for function 'add', got 1".to_string(),
+            )
+            .label(
+                "math.xpl",
+                Span::new(Pos::new(2, 1), Pos::new(2, 20)),
+                "function add is defined here".to_string(),
+            );
+        diags
+            .warning(
+                "main.xpl",
+                Span::default(),
+                Code::Unreachable,
+                "Unreachable code".into(),
+            )
+            .suggest("ad", ["add"]);
+        diags
+    }
+
+    #[test]
+    fn json_lines_carry_code_span_and_related_locations() {
+        let out = to_json(&sample());
+        let lines: Vec<Value> = out
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(
+            lines[0],
+            json!({
+                "severity": "error",
+                "code": "XPL0003",
+                "message": "Expected 2 args for function 'add', got 1",
+                "file": "main.xpl",
+                "span": { "start": { "line": 3, "col": 5 }, "end": { "line": 3, "col": 8 } },
+                "related": [{
+                    "file": "math.xpl",
+                    "span": { "start": { "line": 2, "col": 1 }, "end": { "line": 2, "col": 20 } },
+                    "message": "function add is defined here",
+                }],
+                "notes": [],
+                "suggestion": null,
+            })
+        );
+        assert_eq!(lines[1]["suggestion"], "add");
+    }
+
+    #[test]
+    fn sarif_lists_rules_and_results() {
+        let log = to_sarif(&sample());
+        let run = &log["runs"][0];
+        assert_eq!(log["version"], "2.1.0");
+        assert_eq!(
+            run["tool"]["driver"]["rules"].as_array().unwrap().len(),
+            CODES.len()
+        );
+        let first = &run["results"][0];
+        assert_eq!(first["ruleId"], "XPL0003");
+        assert_eq!(first["ruleIndex"], 2);
+        assert_eq!(first["level"], "error");
+        let region = &first["locations"][0]["physicalLocation"]["region"];
+        assert_eq!(
+            (&region["startLine"], &region["endColumn"]),
+            (&json!(3), &json!(8))
+        );
+        let related = &first["relatedLocations"][0];
+        assert_eq!(
+            related["physicalLocation"]["artifactLocation"]["uri"],
+            "math.xpl"
+        );
+        let second = &run["results"][1];
+        assert_eq!(second["level"], "warning");
+        assert!(
+            second["locations"][0]["physicalLocation"]
+                .get("region")
+                .is_none()
+        );
+        assert_eq!(
+            second["message"]["text"],
+            "Unreachable code\nhelp: did you mean `add`?"
+        );
+    }
+}
@@ -1,64 +1,89 @@
 // src/error.rs
 
+use crate::codes::Code;
+use crate::diagnostics::Diagnostic;
+use crate::limits::Limit;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
 pub enum XplError {
-    #[error("IO error in {file}: {source}")]
+    #[error("{}", io_message(.file, .source, .included_from))]
     Io {
         source: std::io::Error,
         file: String,
+        /// The `include` attribute that named the file, if it was a library
+        included_from: Option<IncludeSite>,
     },
 
-    #[error("XML parse error in {file}: {source}")]
+    #[error("{file}:{line}:{col}: XML parse error: {}", .source.msg())]
     Xml {
-        source: xmltree::ParseError,
+        source: xml::reader::Error,
         file: String,
+        line: usize,
+        col: usize,
     },
 
     #[error("{file}:{line}:{col}: {msg}")]
     Semantic {
+        code: Code,
         msg: String,
         file: String,
         line: usize,
         col: usize,
     },
+
+    /// The script was stopped by one of the VM's limits at the given position
+    #[error("{file}:{line}:{col}: {limit}")]
+    LimitExceeded {
+        limit: Limit,
+        file: String,
+        line: usize,
+        col: usize,
+    },
+}
+
+/// Where a library was named in the `include` attribute of a program
+#[derive(Debug, Clone, PartialEq)]
+pub struct IncludeSite {
+    pub file: String,
+    pub line: usize,
+    pub col: usize,
+}
+
+fn io_message(file: &str, source: &std::io::Error, site: &Option<IncludeSite>) -> String {
+    match site {
+        Some(site) => format!(
+            "{}:{}:{}: Cannot read included file {}: {}",
+            site.file, site.line, site.col, file, source
+        ),
+        None => format!("IO error in {}: {}", file, source),
+    }
 }
 
 impl XplError {
-    /// Print the error with colors and source arrow
-    pub fn pretty_print(&self) {
-        use ansi_term::Colour::{Blue, Red, Yellow};
+    /// Registry code identifying the kind of error
+    pub fn code(&self) -> Code {
         match self {
-            XplError::Io { source, file } => {
-                eprintln!("{}: {} in file {}", Red.bold().paint("error"), source, file);
-            }
-            XplError::Xml { source, file } => {
-                eprintln!("{}: {} in file {}", Red.bold().paint("error"), source, file);
-            }
-            XplError::Semantic {
-                msg,
-                file,
-                line,
-                col,
-            } => {
-                // header
-                eprintln!("{}: {}", Red.bold().paint("error"), Yellow.paint(msg));
-                // location
-                eprintln!("  {} {}:{}:{}", Blue.paint("-->"), file, line, col);
-                // source context
-                if let Ok(src) = std::fs::read_to_string(file) {
-                    if let Some(src_line) = src.lines().nth(*line - 1) {
-                        // blank gutter line
-                        eprintln!("  {}", Blue.paint("|"));
-                        // code line without number
-                        eprintln!("  {} {}", Blue.paint("|"), src_line);
-                        // arrow line (align caret under code)
-                        let indent = " ".repeat(col.saturating_sub(1));
-                        eprintln!("  {} {}{}", Blue.paint("|"), indent, Red.paint("^"));
-                    }
-                }
-            }
+            XplError::Io { .. } => Code::Io,
+            XplError::Xml { .. } => Code::Xml,
+            XplError::Semantic { code, .. } => *code,
+            XplError::LimitExceeded { .. } => Code::LimitExceeded,
         }
     }
+
+    /// An error for a native function to return; the VM locates it at the failing call
+    pub fn native(msg: impl Into<String>) -> Self {
+        XplError::Semantic {
+            code: Code::NativeFailed,
+            msg: msg.into(),
+            file: String::new(),
+            line: 0,
+            col: 0,
+        }
+    }
+
+    /// Print the error with its source, colored when stderr is a terminal
+    pub fn pretty_print(&self) {
+        Diagnostic::from(self).pretty_print();
+    }
 }
@@ -0,0 +1,590 @@
+// src/expr.rs
+
+use crate::codes::Code;
+use crate::dom::Text;
+use crate::limits::MAX_EXPR_DEPTH;
+use crate::parser::{BinOp, Expr, ExprKind, UnOp};
+use crate::span::Span;
+use num_bigint::BigInt;
+use rust_decimal::Decimal;
+
+/// One run of an element's mixed content: raw text, or an already parsed child element
+pub(crate) enum Piece<'a> {
+    Text(&'a Text),
+    Expr(Expr),
+}
+
+/// Error produced while tokenizing or parsing an expression
+#[derive(Debug)]
+pub(crate) struct ExprError {
+    pub code: Code,
+    pub msg: String,
+    pub span: Span,
+}
+
+#[derive(Debug)]
+enum Tok {
+    Int(i64),
+    BigInt(BigInt),
+    Float(f64),
+    Decimal(Decimal),
+    Str(String),
+    Ident(String),
+    Sym(&'static str),
+    Embedded(Expr),
+}
+
+#[derive(Debug)]
+struct Token {
+    tok: Tok,
+    span: Span,
+}
+
+const SYMBOLS: [&str; 14] = [
+    "==", "!=", "<=", ">=", "<", ">", "+", "-", "*", "/", "%", "(", ")", ",",
+];
+
+/// Split mixed content into tokens; child elements become single embedded tokens
+fn tokenize(pieces: Vec<Piece>) -> Result<Vec<Token>, ExprError> {
+    let mut tokens = Vec::new();
+    for p in pieces {
+        let (text, source) = match p {
+            Piece::Expr(e) => {
+                tokens.push(Token {
+                    span: e.span,
+                    tok: Tok::Embedded(e),
+                });
+                continue;
+            }
+            Piece::Text(t) => (t.text.as_str(), t),
+        };
+        let err = |msg: String, offset: usize| ExprError {
+            code: Code::InvalidExpression,
+            msg,
+            span: source.span_at(offset, 1),
+        };
+        let mut chars = text.char_indices().peekable();
+        while let Some(&(offset, c)) = chars.peek() {
+            let tok = if c.is_whitespace() {
+                chars.next();
+                continue;
+            } else if c.is_ascii_digit() {
+                let mut end = offset;
+                while let Some(&(i, d)) = chars.peek() {
+                    if !d.is_ascii_digit() {
+                        break;
+                    }
+                    end = i + d.len_utf8();
+                    chars.next();
+                }
+                // a '.' followed by a digit continues the literal as a float
+                let frac = text[end..].strip_prefix('.').and_then(|rest| {
+                    let len = rest
+                        .find(|d: char| !d.is_ascii_digit())
+                        .unwrap_or(rest.len());
+                    (len > 0).then_some(len)
+                });
+                if let Some(len) = frac {
+                    end += 1 + len;
+                }
+                // a `d` right after the number, not starting a word, makes it a decimal
+                let mut suffix = text[end..].chars();
+                let decimal = suffix.next() == Some('d')
+                    && !suffix
+                        .next()
+                        .is_some_and(|c| c.is_alphanumeric() || c == '_');
+                let digits = &text[offset..end];
+                if decimal {
+                    end += 1;
+                }
+                while chars.peek().is_some_and(|&(i, _)| i < end) {
+                    chars.next();
+                }
+                if decimal {
+                    let d = Decimal::from_str_exact(digits).map_err(|_| {
+                        err(
+                            format!("Decimal literal {}d is out of range", digits),
+                            offset,
+                        )
+                    })?;
+                    Tok::Decimal(d)
+                } else if frac.is_some() {
+                    // digits-only text always parses as f64
+                    Tok::Float(digits.parse().unwrap())
+                } else {
+                    match digits.parse::<i64>() {
+                        Ok(n) => Tok::Int(n),
+                        // digits-only text always parses as a BigInt; whether it is allowed
+                        // depends on the program's arithmetic, checked by analysis
+                        Err(_) => Tok::BigInt(digits.parse().unwrap()),
+                    }
+                }
+            } else if c.is_alphabetic() || c == '_' {
+                let mut end = offset;
+                while let Some(&(i, d)) = chars.peek() {
+                    if !(d.is_alphanumeric() || d == '_') {
+                        break;
+                    }
+                    end = i + d.len_utf8();
+                    chars.next();
+                }
+                Tok::Ident(text[offset..end].to_string())
+            } else if c == '"' {
+                chars.next();
+                let mut s = String::new();
+                loop {
+                    match chars.next() {
+                        Some((_, '"')) => break,
+                        Some((i, '\\')) => match chars.next() {
+                            Some((_, 'n')) => s.push('\n'),
+                            Some((_, 't')) => s.push('\t'),
+                            Some((_, e @ ('"' | '\\'))) => s.push(e),
+                            _ => return Err(err("Invalid escape sequence".to_string(), i)),
+                        },
+                        Some((_, ch)) => s.push(ch),
+                        None => {
+                            return Err(err("Unterminated string literal".to_string(), offset));
+                        }
+                    }
+                }
+                Tok::Str(s)
+            } else if let Some(sym) = SYMBOLS.iter().find(|s| text[offset..].starts_with(**s)) {
+                for _ in 0..sym.len() {
+                    chars.next();
+                }
+                Tok::Sym(sym)
+            } else {
+                return Err(err(format!("Unexpected character '{}'", c), offset));
+            };
+            let end = chars.peek().map_or(text.len(), |&(i, _)| i);
+            tokens.push(Token {
+                tok,
+                span: source.span_at(offset, end - offset),
+            });
+        }
+    }
+    Ok(tokens)
+}
+
+/// Binding power of prefix `not`: looser than comparisons, tighter than `and`
+const NOT_BP: u8 = 3;
+
+/// Binary operator for a token, with its binding power (higher binds tighter)
+fn binary_op(tok: &Tok) -> Option<(BinOp, u8)> {
+    let sym = match tok {
+        Tok::Sym(sym) => *sym,
+        Tok::Ident(word) => word.as_str(),
+        _ => return None,
+    };
+    Some(match sym {
+        "or" => (BinOp::Or, 1),
+        "and" => (BinOp::And, 2),
+        "==" => (BinOp::Equal, 4),
+        "!=" => (BinOp::NotEqual, 4),
+        "<" => (BinOp::Less, 4),
+        "<=" => (BinOp::LessEqual, 4),
+        ">" => (BinOp::Greater, 4),
+        ">=" => (BinOp::GreaterEqual, 4),
+        "+" => (BinOp::Add, 5),
+        "-" => (BinOp::Subtract, 5),
+        "*" => (BinOp::Multiply, 6),
+        "/" => (BinOp::Divide, 6),
+        "%" => (BinOp::Modulus, 6),
+        _ => return None,
+    })
+}
+
+struct ExprParser {
+    tokens: std::iter::Peekable<std::vec::IntoIter<Token>>,
+    /// Where input ends, reported when it ends unexpectedly
+    end: Span,
+    /// Levels of operator elements around the text being parsed
+    outer: usize,
+    /// Recursive calls currently parsing a nested operand, counting from `outer`
+    nesting: usize,
+}
+
+/// Depth of the deepest operand of an expression, found without recursing
+pub(crate) fn depth(expr: &Expr) -> usize {
+    let mut deepest = 0;
+    let mut pending = vec![(expr, 1)];
+    while let Some((e, d)) = pending.pop() {
+        deepest = deepest.max(d);
+        match &e.kind {
+            ExprKind::BinaryOp(_, l, r) => pending.extend([(&**l, d + 1), (&**r, d + 1)]),
+            ExprKind::UnaryOp(_, operand) => pending.push((operand, d + 1)),
+            ExprKind::Call(_, args) => pending.extend(args.iter().map(|a| (a, d + 1))),
+            _ => {}
+        }
+    }
+    deepest
+}
+
+pub(crate) fn too_deep(span: Span) -> ExprError {
+    ExprError {
+        code: Code::TooDeep,
+        msg: format!(
+            "Expression is nested more than {} levels deep",
+            MAX_EXPR_DEPTH
+        ),
+        span,
+    }
+}
+
+impl ExprParser {
+    /// Whether the next token is the symbol `sym`
+    fn at_sym(&mut self, sym: &str) -> bool {
+        matches!(self.tokens.peek(), Some(Token { tok: Tok::Sym(s), .. }) if *s == sym)
+    }
+
+    /// Error at a token, or at the end of input when there is none
+    fn error_at(&self, tok: Option<&Token>, msg: String) -> ExprError {
+        let span = tok.map_or(self.end, |t| t.span);
+        ExprError {
+            code: Code::InvalidExpression,
+            msg,
+            span,
+        }
+    }
+
+    /// Run `parse` one level deeper, failing before the parser's own stack runs out
+    fn nested(
+        &mut self,
+        parse: impl FnOnce(&mut Self) -> Result<Expr, ExprError>,
+    ) -> Result<Expr, ExprError> {
+        if self.nesting == MAX_EXPR_DEPTH {
+            let span = self.tokens.peek().map_or(self.end, |t| t.span);
+            return Err(too_deep(span));
+        }
+        self.nesting += 1;
+        let res = parse(self);
+        self.nesting -= 1;
+        res
+    }
+
+    /// Precedence climbing: parse operators binding at least as tightly as `min_bp`
+    fn parse_bp(&mut self, min_bp: u8) -> Result<Expr, ExprError> {
+        self.nested(|p| p.parse_operators(min_bp))
+    }
+
+    fn parse_operators(&mut self, min_bp: u8) -> Result<Expr, ExprError> {
+        let mut lhs = match self.tokens.peek() {
+            Some(Token {
+                tok: Tok::Ident(w),
+                span,
+            }) if w == "not" => {
+                let start = *span;
+                self.tokens.next();
+                let operand = self.parse_bp(NOT_BP)?;
+                let span = start.to(operand.span);
+                Expr::new(ExprKind::UnaryOp(UnOp::Not, Box::new(operand)), span)
+            }
+            _ => self.parse_unary()?,
+        };
+        // a chain like `1 + 2 + 3` is parsed in this loop but nests in the tree it builds
+        let mut lhs_depth = self.outer + depth(&lhs);
+        if lhs_depth > MAX_EXPR_DEPTH {
+            return Err(too_deep(lhs.span));
+        }
+        while let Some(token) = self.tokens.peek() {
+            let Some((op, bp)) = binary_op(&token.tok) else {
+                break;
+            };
+            if bp < min_bp {
+                break;
+            }
+            let op_span = token.span;
+            self.tokens.next();
+            let rhs = self.parse_bp(bp + 1)?;
+            lhs_depth = lhs_depth.max(self.outer + depth(&rhs)) + 1;
+            if lhs_depth > MAX_EXPR_DEPTH {
+                return Err(too_deep(op_span));
+            }
+            let span = lhs.span.to(rhs.span);
+            lhs = Expr::new(ExprKind::BinaryOp(op, Box::new(lhs), Box::new(rhs)), span);
+        }
+        Ok(lhs)
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, ExprError> {
+        if let Some(Token {
+            tok: Tok::Sym("-"),
+            span,
+        }) = self.tokens.peek()
+        {
+            let start = *span;
+            self.tokens.next();
+            let operand = self.nested(Self::parse_unary)?;
+            let span = start.to(operand.span);
+            let kind = match operand.kind {
+                // fold negative literals so `-5` stays a plain number; negating the smallest
+                // int is left to run, under the program's overflow policy
+                ExprKind::LiteralInt(i) => match i.checked_neg() {
+                    Some(n) => ExprKind::LiteralInt(n),
+                    None => ExprKind::UnaryOp(UnOp::Negate, Box::new(operand)),
+                },
+                // the smallest int can only be written as a negated out of range literal
+                ExprKind::LiteralBigInt(i) => match i64::try_from(-&i) {
+                    Ok(n) => ExprKind::LiteralInt(n),
+                    Err(_) => ExprKind::LiteralBigInt(-i),
+                },
+                ExprKind::LiteralFloat(f) => ExprKind::LiteralFloat(-f),
+                ExprKind::LiteralDecimal(d) => ExprKind::LiteralDecimal(-d),
+                _ => ExprKind::UnaryOp(UnOp::Negate, Box::new(operand)),
+            };
+            return Ok(Expr::new(kind, span));
+        }
+        self.parse_primary()
+    }
+
+    fn parse_primary(&mut self) -> Result<Expr, ExprError> {
+        let Some(token) = self.tokens.next() else {
+            return Err(self.error_at(None, "Expected expression".to_string()));
+        };
+        let span = token.span;
+        let kind = match token.tok {
+            Tok::Int(i) => ExprKind::LiteralInt(i),
+            Tok::BigInt(i) => ExprKind::LiteralBigInt(i),
+            Tok::Float(f) => ExprKind::LiteralFloat(f),
+            Tok::Decimal(d) => ExprKind::LiteralDecimal(d),
+            Tok::Str(s) => ExprKind::LiteralStr(s),
+            Tok::Ident(word) => match word.as_str() {
+                "true" => ExprKind::LiteralBool(true),
+                "false" => ExprKind::LiteralBool(false),
+                "null" => ExprKind::LiteralNull,
+                "and" | "or" | "not" => {
+                    return Err(ExprError {
+                        code: Code::InvalidExpression,
+                        msg: format!("Unexpected '{}'", word),
+                        span,
+                    });
+                }
+                // `name(args)` is shorthand for <call function="name">
+                _ if self.at_sym("(") => {
+                    self.tokens.next();
+                    let (args, close) = self.parse_args()?;
+                    return Ok(Expr::new(ExprKind::Call(word, args), span.to(close)));
+                }
+                _ => ExprKind::VarRef(word),
+            },
+            Tok::Embedded(e) => return Ok(e),
+            Tok::Sym("(") => {
+                let mut inner = self.parse_bp(0)?;
+                return match self.tokens.next() {
+                    Some(Token {
+                        tok: Tok::Sym(")"),
+                        span: close,
+                    }) => {
+                        // the parentheses belong to the expression they group
+                        inner.span = span.to(close);
+                        Ok(inner)
+                    }
+                    other => Err(self.error_at(other.as_ref(), "Expected ')'".to_string())),
+                };
+            }
+            Tok::Sym(sym) => {
+                return Err(self.error_at(Some(&token), format!("Unexpected '{}'", sym)));
+            }
+        };
+        Ok(Expr::new(kind, span))
+    }
+
+    /// Comma-separated call arguments after the opening parenthesis, with the closing one's span
+    fn parse_args(&mut self) -> Result<(Vec<Expr>, Span), ExprError> {
+        let mut args = Vec::new();
+        if self.at_sym(")") {
+            let close = self.tokens.next().unwrap().span;
+            return Ok((args, close));
+        }
+        loop {
+            args.push(self.parse_bp(0)?);
+            match self.tokens.next() {
+                Some(Token {
+                    tok: Tok::Sym(")"),
+                    span,
+                }) => return Ok((args, span)),
+                Some(Token {
+                    tok: Tok::Sym(","), ..
+                }) => {}
+                other => {
+                    return Err(self.error_at(other.as_ref(), "Expected ',' or ')'".to_string()));
+                }
+            }
+        }
+    }
+}
+
+/// Parse mixed text and element content as one infix expression, with `outer` levels of
+/// operator elements around it
+pub(crate) fn parse(pieces: Vec<Piece>, outer: usize) -> Result<Expr, ExprError> {
+    let end = match pieces.last() {
+        Some(Piece::Text(t)) => t.span_at(t.text.trim_end().len(), 0),
+        Some(Piece::Expr(e)) => Span::new(e.span.end, e.span.end),
+        None => Span::default(),
+    };
+    let tokens = tokenize(pieces)?;
+    let mut p = ExprParser {
+        tokens: tokens.into_iter().peekable(),
+        end,
+        outer,
+        nesting: outer,
+    };
+    let expr = p.parse_bp(0)?;
+    match p.tokens.next() {
+        None => Ok(expr),
+        Some(t) => Err(p.error_at(Some(&t), "Unexpected token after expression".to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::span::Pos;
+
+    fn text(s: &str) -> Text {
+        Text::new(s.to_string(), Pos::new(1, 1))
+    }
+
+    fn parse_str(s: &str) -> Result<Expr, ExprError> {
+        parse(vec![Piece::Text(&text(s))], 0)
+    }
+
+    fn bin(op: BinOp, l: Expr, r: Expr) -> Expr {
+        Expr::from(ExprKind::BinaryOp(op, Box::new(l), Box::new(r)))
+    }
+
+    fn var(name: &str) -> Expr {
+        Expr::from(ExprKind::VarRef(name.to_string()))
+    }
+
+    fn int(i: i64) -> Expr {
+        Expr::from(ExprKind::LiteralInt(i))
+    }
+
+    #[test]
+    fn precedence_and_parentheses() {
+        assert_eq!(
+            parse_str("a + b * c").unwrap(),
+            bin(
+                BinOp::Add,
+                var("a"),
+                bin(BinOp::Multiply, var("b"), var("c"))
+            )
+        );
+        assert_eq!(
+            parse_str("(a+b)*c").unwrap(),
+            bin(
+                BinOp::Multiply,
+                bin(BinOp::Add, var("a"), var("b")),
+                var("c")
+            )
+        );
+        assert_eq!(
+            parse_str("a - b - c").unwrap(),
+            bin(
+                BinOp::Subtract,
+                bin(BinOp::Subtract, var("a"), var("b")),
+                var("c")
+            )
+        );
+        assert_eq!(
+            parse_str("n-1 == -x").unwrap(),
+            bin(
+                BinOp::Equal,
+                bin(BinOp::Subtract, var("n"), int(1)),
+                Expr::from(ExprKind::UnaryOp(UnOp::Negate, Box::new(var("x"))))
+            )
+        );
+        assert_eq!(parse_str(" -5 ").unwrap(), int(-5));
+        assert_eq!(
+            parse_str("\"a + b\"").unwrap(),
+            Expr::from(ExprKind::LiteralStr("a + b".to_string()))
+        );
+    }
+
+    #[test]
+    fn logical_operators() {
+        assert_eq!(
+            parse_str("not a == b and c or false").unwrap(),
+            bin(
+                BinOp::Or,
+                bin(
+                    BinOp::And,
+                    Expr::from(ExprKind::UnaryOp(
+                        UnOp::Not,
+                        Box::new(bin(BinOp::Equal, var("a"), var("b")))
+                    )),
+                    var("c")
+                ),
+                Expr::from(ExprKind::LiteralBool(false))
+            )
+        );
+        assert!(parse_str("a and").is_err());
+        assert!(parse_str("or b").is_err());
+    }
+
+    #[test]
+    fn mixed_content() {
+        let call = Expr::from(ExprKind::Call("f".to_string(), vec![]));
+        let (before, after) = (text(" base * "), text(" + 1"));
+        let expr = parse(
+            vec![
+                Piece::Text(&before),
+                Piece::Expr(call.clone()),
+                Piece::Text(&after),
+            ],
+            0,
+        )
+        .unwrap();
+        assert_eq!(
+            expr,
+            bin(BinOp::Add, bin(BinOp::Multiply, var("base"), call), int(1))
+        );
+    }
+
+    #[test]
+    fn spans() {
+        let expr = parse_str("a +\n (-b * 2)").unwrap();
+        assert_eq!(expr.span, Span::new(Pos::new(1, 1), Pos::new(2, 10)));
+        let ExprKind::BinaryOp(_, _, rhs) = &expr.kind else {
+            panic!("expected a binary operation");
+        };
+        assert_eq!(rhs.span, Span::new(Pos::new(2, 2), Pos::new(2, 10)));
+        let call = parse_str("f(x, 1)").unwrap();
+        assert_eq!(call.span, Span::new(Pos::new(1, 1), Pos::new(1, 8)));
+    }
+
+    #[test]
+    fn error_offsets() {
+        let err = parse_str("a + * b").unwrap_err();
+        assert_eq!(err.span.start, Pos::new(1, 5));
+        let err = parse_str("(a + b").unwrap_err();
+        assert_eq!(err.msg, "Expected ')'");
+        assert_eq!(err.span.start, Pos::new(1, 7));
+        let err = parse_str("a\n b").unwrap_err();
+        assert_eq!(err.span.start, Pos::new(2, 2));
+        assert!(parse_str("   ").is_err());
+    }
+
+    #[test]
+    fn number_literals() {
+        let lit = |s: &str| parse_str(s).unwrap().kind;
+        assert_eq!(
+            lit("19.90d"),
+            ExprKind::LiteralDecimal("19.90".parse().unwrap())
+        );
+        assert_eq!(lit("-3d"), ExprKind::LiteralDecimal((-3).into()));
+        assert_eq!(lit("-9223372036854775808"), ExprKind::LiteralInt(i64::MIN));
+        assert_eq!(
+            lit("-(-9223372036854775808)"),
+            ExprKind::UnaryOp(UnOp::Negate, Box::new(int(i64::MIN)))
+        );
+        assert_eq!(
+            lit("9223372036854775808"),
+            ExprKind::LiteralBigInt("9223372036854775808".parse().unwrap())
+        );
+        // `d` only ends a number when no word follows it
+        assert!(parse_str("2dx").is_err());
+        assert!(parse_str("1d0").is_err());
+    }
+}
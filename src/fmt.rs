@@ -0,0 +1,222 @@
+// src/fmt.rs
+
+use crate::dom;
+use crate::error::XplError;
+
+const INDENT: &str = "  ";
+
+/// Read and format a script, failing if it is not well-formed XML
+pub fn format_file(path: &str) -> Result<String, XplError> {
+    let src = std::fs::read_to_string(path).map_err(|e| XplError::Io {
+        source: e,
+        file: path.to_string(),
+        included_from: None,
+    })?;
+    dom::parse(&src).map_err(|e| {
+        let pos = dom::error_pos(&e);
+        XplError::Xml {
+            source: e,
+            file: path.to_string(),
+            line: pos.line,
+            col: pos.col,
+        }
+    })?;
+    Ok(format_source(&src))
+}
+
+/// Re-indent a document by element nesting, two spaces per level. Only whitespace at the
+/// start and end of lines changes; lines inside a multi-line string literal or CDATA section
+/// are kept as is.
+pub fn format_source(src: &str) -> String {
+    let newline = if src.contains("\r\n") { "\r\n" } else { "\n" };
+    let mut scan = Scanner::default();
+    let mut out = Vec::new();
+    for line in src.lines() {
+        if scan.verbatim() {
+            scan.line(line);
+            // whitespace after the closing quote or `]]>` is not part of the text
+            let line = if scan.verbatim() {
+                line
+            } else {
+                line.trim_end()
+            };
+            out.push(line.to_string());
+            continue;
+        }
+        let text = line.trim();
+        if text.is_empty() {
+            // keep paragraphs apart, but never more than one blank line
+            if out.last().is_some_and(|l: &String| !l.is_empty()) {
+                out.push(String::new());
+            }
+            continue;
+        }
+        let level = if scan.in_tag {
+            // continuation of a start tag, such as attributes on their own lines
+            scan.depth + 1
+        } else if text.starts_with("</") {
+            scan.depth - 1
+        } else {
+            scan.depth
+        };
+        scan.line(text);
+        // a string or CDATA section opened on this line keeps its trailing whitespace
+        let text = if scan.verbatim() {
+            line.trim_start()
+        } else {
+            text
+        };
+        out.push(format!("{}{}", INDENT.repeat(level.max(0) as usize), text));
+    }
+    while out.last().is_some_and(|l| l.is_empty()) {
+        out.pop();
+    }
+    out.join(newline) + newline
+}
+
+/// Nesting depth after the input so far, or below zero if more closes than opens were seen
+pub fn depth(src: &str) -> i32 {
+    let mut scan = Scanner::default();
+    for line in src.lines() {
+        scan.line(line);
+    }
+    if scan.in_tag || scan.in_comment || scan.verbatim() {
+        scan.depth + 1
+    } else {
+        scan.depth
+    }
+}
+
+/// Tracks element nesting across lines
+#[derive(Default)]
+struct Scanner {
+    depth: i32,
+    /// Inside a start tag that continues on the next line
+    in_tag: bool,
+    /// Inside `<!-- -->` or a declaration, up to the given terminator
+    in_comment: bool,
+    terminator: &'static str,
+    /// Inside a `"` string literal in element text
+    in_string: bool,
+    /// Inside `<![CDATA[ ]]>`, whose text is kept as written
+    in_cdata: bool,
+    /// The quote of an attribute value being read, when inside one
+    quote: Option<char>,
+    closing: bool,
+}
+
+impl Scanner {
+    /// Whether the current line continues text that must not be reindented
+    fn verbatim(&self) -> bool {
+        self.in_string || self.in_cdata
+    }
+
+    fn line(&mut self, line: &str) {
+        let mut rest = line;
+        while let Some(c) = rest.chars().next() {
+            if self.in_cdata {
+                match rest.find("]]>") {
+                    Some(i) => {
+                        rest = &rest[i + 3..];
+                        self.in_cdata = false;
+                    }
+                    None => return,
+                }
+                continue;
+            }
+            if self.in_comment {
+                match rest.find(self.terminator) {
+                    Some(i) => {
+                        rest = &rest[i + self.terminator.len()..];
+                        self.in_comment = false;
+                    }
+                    None => return,
+                }
+                continue;
+            }
+            if self.in_tag {
+                if let Some(q) = self.quote {
+                    if c == q {
+                        self.quote = None;
+                    }
+                } else if c == '"' || c == '\'' {
+                    self.quote = Some(c);
+                } else if rest.starts_with("/>") {
+                    self.in_tag = false;
+                    rest = &rest[2..];
+                    continue;
+                } else if c == '>' {
+                    self.in_tag = false;
+                    self.depth += if self.closing { -1 } else { 1 };
+                }
+            } else if self.in_string {
+                if c == '"' {
+                    self.in_string = false;
+                }
+            } else if c == '"' {
+                self.in_string = true;
+            } else if let Some(cdata) = rest.strip_prefix("<![CDATA[") {
+                self.in_cdata = true;
+                rest = cdata;
+                continue;
+            } else if c == '<' {
+                for (start, end) in [("<!--", "-->"), ("<?", "?>")] {
+                    if rest.starts_with(start) {
+                        self.in_comment = true;
+                        self.terminator = end;
+                        rest = &rest[start.len()..];
+                        break;
+                    }
+                }
+                if self.in_comment {
+                    continue;
+                }
+                self.in_tag = true;
+                self.closing = rest.starts_with("</");
+            }
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reindents_by_nesting() {
+        let src = "<program name=\"p\"\n        include=\"a.xpl\">\n\t<function name=\"main\">\n <body>\n\n\n<!-- a <comment>\n   over lines -->\n      <print>\"a\n   b\"</print>\n<if><condition>x &gt; 1</condition>\n<then><break/></then></if>\n   </body>\n</function>\n</program>\n\n";
+        let expected = "<program name=\"p\"\n  include=\"a.xpl\">\n  <function name=\"main\">\n    <body>\n\n      <!-- a <comment>\n      over lines -->\n      <print>\"a\n   b\"</print>\n      <if><condition>x &gt; 1</condition>\n        <then><break/></then></if>\n    </body>\n  </function>\n</program>\n";
+        assert_eq!(format_source(src), expected);
+        // formatting is stable
+        assert_eq!(format_source(expected), expected);
+        assert_eq!(depth(src), 0);
+        assert_eq!(depth("<body><if>\n<then>"), 3);
+        assert_eq!(depth("<print>\"open"), 2);
+    }
+
+    #[test]
+    fn keeps_whitespace_inside_strings_and_cdata() {
+        let run = |src: &str| {
+            let capture = crate::vm::Capture::default();
+            let mut vm = crate::vm::VM::builder().output(capture.clone()).build();
+            let prog = vm.load_str(src, "<test>").unwrap();
+            vm.run(&prog).unwrap();
+            capture.take_lines()
+        };
+        for print in [
+            "<print>\"a  \n    b \n  \n c\"  \n</print>",
+            "<print><![CDATA[\"a < \n    b &\n  \n c\"]]>  \n</print>",
+        ] {
+            let src = format!(
+                "<program name=\"p\">\n<function name=\"main\"><body>\n{}\n</body></function>\n</program>\n",
+                print
+            );
+            let formatted = format_source(&src);
+            assert_ne!(formatted, src);
+            assert_eq!(run(&formatted), run(&src));
+            assert_eq!(format_source(&formatted), formatted);
+        }
+        assert_eq!(depth("<print><![CDATA[\"a\n</print>"), 2);
+    }
+}
@@ -1,21 +1,148 @@
 // src/lib.rs
 
+pub mod analysis;
+pub mod builtins;
+pub mod callee;
+pub mod codes;
+pub mod diagnostics;
+pub mod doc;
+mod dom;
+pub mod emit;
 pub mod error;
+mod expr;
+pub mod fmt;
+pub mod limits;
+pub mod native;
 pub mod parser;
+mod render;
+pub mod span;
+pub mod typeck;
+pub mod value;
 pub mod vm;
 
+pub use codes::Code;
+pub use diagnostics::{Diagnostic, Diagnostics, Label, Severity};
 pub use error::XplError;
+pub use limits::Limits;
+pub use native::Signature;
+pub use num_bigint::BigInt;
+pub use rust_decimal::Decimal;
+pub use span::{Pos, Span};
+pub use value::Value;
 
-/// Run an XPL script from the given file path, returning printed outputs
+/// Parse a script and run every static check over it.
+/// Only files that cannot be read or are not well-formed XML fail outright.
+fn load(path: &str, diags: &mut Diagnostics) -> Result<parser::Program, XplError> {
+    load_with(path, &[], diags)
+}
+
+/// Like `load`, for a host that provides the `natives` functions
+fn load_with(
+    path: &str,
+    natives: &[Signature],
+    diags: &mut Diagnostics,
+) -> Result<parser::Program, XplError> {
+    let program = parser::parse_file_with(path, diags)?;
+    analysis::check_with(&program, natives, &[], diags);
+    typeck::check_with(&program, natives, diags);
+    Ok(program)
+}
+
+/// Check an XPL script without running any of it, collecting every problem found
+pub fn check_file(path: &str) -> Diagnostics {
+    check_file_with(path, &[])
+}
+
+/// Like `check_file`, for scripts that call the given native functions,
+/// such as those from `VM::native_signatures`
+pub fn check_file_with(path: &str, natives: &[Signature]) -> Diagnostics {
+    let mut diags = Diagnostics::new();
+    if let Err(err) = load_with(path, natives, &mut diags) {
+        diags.push(err.into());
+    }
+    diags
+}
+
+/// Run an XPL script from the given file path, returning printed outputs.
+/// Fails with the first error `check_file` would report before running anything.
 pub fn run_file(path: &str) -> Result<Vec<String>, XplError> {
-    let program = parser::parse_file(path)?;
-    // If no main function, treat as empty output
+    run_file_with_args(path, &[]).map(|run| run.outputs)
+}
+
+/// Outcome of a script that ran to completion
+#[derive(Debug)]
+pub struct Run {
+    pub outputs: Vec<String>,
+    /// The int main returned, or 0
+    pub status: i32,
+}
+
+/// Like `run_file`, passing `args` to main's params and to the `args()` builtin
+pub fn run_file_with_args(path: &str, args: &[String]) -> Result<Run, XplError> {
+    let capture = vm::Capture::default();
+    let status = run_file_to(path, args, capture.clone())?;
+    Ok(Run {
+        outputs: capture.take_lines(),
+        status,
+    })
+}
+
+/// Run a script with `args`, writing what it prints to `out` as it runs, and return
+/// its exit status. Output printed before a runtime error has already been written.
+pub fn run_file_to(
+    path: &str,
+    args: &[String],
+    out: impl std::io::Write + 'static,
+) -> Result<i32, XplError> {
+    let mut diags = Diagnostics::new();
+    let program = load(path, &mut diags)?;
+    if let Some(diag) = diags.first_error() {
+        return Err(diag.clone().into());
+    }
+    // If no main function, there is nothing to run
     if !program.functions.contains_key("main") {
-        return Ok(Vec::new());
+        return Ok(0);
     }
     let mut vm = vm::VM::new(path.to_string());
-    let outputs = vm.run(&program)?;
-    Ok(outputs)
+    vm.set_args(args.to_vec());
+    vm.set_output(out);
+    vm.run(&program)?;
+    Ok(vm.exit_status())
+}
+
+/// Outcome of one test function
+pub struct TestResult {
+    pub name: String,
+    /// What the test printed, or why it failed
+    pub outcome: Result<Vec<String>, XplError>,
+}
+
+/// Run each function named `test_*` that takes no params and is defined in the file itself,
+/// in source order and each in a fresh VM. Fails with the file's diagnostics if it has errors.
+pub fn test_file(path: &str) -> Result<Vec<TestResult>, Diagnostics> {
+    let mut diags = Diagnostics::new();
+    let program = match load(path, &mut diags) {
+        Ok(program) if !diags.has_errors() => program,
+        Ok(_) => return Err(diags),
+        Err(err) => {
+            diags.push(err.into());
+            return Err(diags);
+        }
+    };
+    let mut tests: Vec<&parser::Function> = program
+        .functions
+        .values()
+        .filter(|f| f.file == path && f.name.starts_with("test_") && f.params.is_empty())
+        .collect();
+    tests.sort_by_key(|f| f.span.start);
+    let results = tests
+        .into_iter()
+        .map(|func| TestResult {
+            name: func.name.clone(),
+            outcome: vm::VM::new(path.to_string()).run_function(&program, func),
+        })
+        .collect();
+    Ok(results)
 }
 
 #[cfg(test)]
@@ -38,8 +165,7 @@ mod tests {
             outputs,
             vec![
                 "Hello, World!".to_string(),
-                "The result of 5 + 3 is: ".to_string(),
-                "8".to_string(),
+                "The result of 5 + 3 is: 8".to_string(),
             ]
         );
     }
@@ -94,4 +220,792 @@ mod tests {
             vec!["Loop".to_string(), "Loop".to_string(), "Loop".to_string()]
         );
     }
+
+    #[test]
+    fn runs_nested_loops() {
+        let tmp = "<program name=\"nested\" version=\"1.0\"><function name=\"main\"><body>\
+            <loop times=\"2\"><print>\"outer\"</print><loop times=\"2\"><print>\"inner\"</print></loop></loop>\
+            </body></function></program>";
+        let path = std::env::temp_dir().join("nested_loops.xpl");
+        std::fs::write(&path, tmp).unwrap();
+        let outputs = run_file(path.to_str().unwrap()).unwrap();
+        assert_eq!(
+            outputs,
+            vec!["outer", "inner", "inner", "outer", "inner", "inner"]
+        );
+    }
+
+    #[test]
+    fn function_bodies_run_every_statement() {
+        let tmp = "<program name=\"fbody\" version=\"1.0\">\
+            <function name=\"first\"><param name=\"n\" type=\"int\"/><body>\
+            <assign var=\"x\">n * 2</assign><print>x</print>\
+            <loop times=\"5\"><if><condition>1</condition><then><return>x</return></then><else/></if></loop>\
+            <print>\"unreachable\"</print></body></function>\
+            <function name=\"main\"><body><assign var=\"x\">1</assign>\
+            <assign var=\"y\"><call function=\"first\"><param>21</param></call></assign>\
+            <print>y</print><print>x</print></body></function></program>";
+        let path = std::env::temp_dir().join("function_bodies.xpl");
+        std::fs::write(&path, tmp).unwrap();
+        let outputs = run_file(path.to_str().unwrap()).unwrap();
+        assert_eq!(outputs, vec!["42", "42", "1"]);
+    }
+
+    #[test]
+    fn comparisons_drive_math_library() {
+        let tmp = "<program name=\"cmp\" include=\"examples/math.xpl\" version=\"1.0\"><function name=\"main\"><body>\
+            <print><call function=\"divide\"><param>10</param><param>2</param></call></print>\
+            <print><call function=\"modulus\"><param>10</param><param>3</param></call></print>\
+            <print><call function=\"factorial\"><param>0</param></call></print>\
+            <print> 3 &lt;= 2 </print><print> 3 != 2 </print>\
+            <print><ge><param>3</param><param>1 + 2</param></ge></print>\
+            </body></function></program>";
+        let path = std::env::temp_dir().join("comparisons.xpl");
+        std::fs::write(&path, tmp).unwrap();
+        let outputs = run_file(path.to_str().unwrap()).unwrap();
+        assert_eq!(outputs, vec!["5", "1", "1", "false", "true", "true"]);
+    }
+
+    #[test]
+    fn infix_and_mixed_content_expressions() {
+        let tmp = "<program name=\"infix\" include=\"examples/math.xpl\" version=\"1.0\"><function name=\"main\"><body>\
+            <assign var=\"x\">2</assign>\
+            <print> (x+1) * -x - 10 % 4 </print>\
+            <print><call function=\"power\"><param>2</param><param>10</param></call></print>\
+            <print><call function=\"factorial\"><param>5</param></call></print>\
+            <print><call function=\"fibonacci\"><param>10</param></call></print>\
+            <print> 1 + <call function=\"add\"><param>x</param><param>x * 2</param></call> * 2 </print>\
+            </body></function></program>";
+        let path = std::env::temp_dir().join("infix.xpl");
+        std::fs::write(&path, tmp).unwrap();
+        let outputs = run_file(path.to_str().unwrap()).unwrap();
+        assert_eq!(outputs, vec!["-8", "1024", "120", "55", "13"]);
+    }
+
+    #[test]
+    fn expression_parse_error_location() {
+        let tmp = "<program name=\"perr\" version=\"1.0\">\n<function name=\"main\"><body>\n  <print>a + * b</print>\n</body></function></program>";
+        let path = std::env::temp_dir().join("expr_parse_err.xpl");
+        std::fs::write(&path, tmp).unwrap();
+        let err = run_file(path.to_str().unwrap()).unwrap_err().to_string();
+        assert!(err.contains(":3:14: Unexpected '*'"), "{}", err);
+    }
+
+    #[test]
+    fn boolean_logic_short_circuits() {
+        let tmp = "<program name=\"logic\" version=\"1.0\"><function name=\"main\"><body>\
+            <assign var=\"x\">5</assign>\
+            <print> false and 1 / 0 == 1 </print>\
+            <print> x &gt; 1 or 1 / 0 == 1 </print>\
+            <print> not (x == 5) </print>\
+            <print><and><gt><param>x</param><param>0</param></gt><lt><param>x</param><param>10</param></lt><param>true</param></and></print>\
+            <print><or><param>x == 1</param><not>x == 5</not></or></print>\
+            </body></function></program>";
+        let path = std::env::temp_dir().join("logic.xpl");
+        std::fs::write(&path, tmp).unwrap();
+        let outputs = run_file(path.to_str().unwrap()).unwrap();
+        assert_eq!(outputs, vec!["false", "true", "false", "true", "false"]);
+    }
+
+    #[test]
+    fn dynamic_values_flow_through_calls() {
+        let tmp = "<program name=\"values\" include=\"examples/math.xpl\" version=\"1.0\">\
+            <function name=\"greet\"><param name=\"who\" type=\"string\"/><body><return>who</return></body></function>\
+            <function name=\"nothing\"><body><return/></body></function>\
+            <function name=\"main\"><body>\
+            <assign var=\"s\">\"hi\"</assign>\
+            <print><call function=\"greet\"><param>s</param></call></print>\
+            <print><call function=\"divide\"><param>1</param><param>0</param></call></print>\
+            <print> 7 / 2.0 </print><print> 1.5 * 2 == 3 </print>\
+            <print><call function=\"nothing\"/></print><print> s &lt; \"hj\" </print>\
+            </body></function></program>";
+        let path = std::env::temp_dir().join("values.xpl");
+        std::fs::write(&path, tmp).unwrap();
+        let outputs = run_file(path.to_str().unwrap()).unwrap();
+        assert_eq!(
+            outputs,
+            vec![
+                "hi",
+                "Error: Division by zero",
+                "3.5",
+                "true",
+                "null",
+                "true"
+            ]
+        );
+    }
+
+    #[test]
+    fn type_error_is_located() {
+        let tmp = "<program name=\"terr\" version=\"1.0\">\n<function name=\"main\"><body>\n<assign var=\"label\">\"a\"</assign>\n<print> 1 - label </print></body></function></program>";
+        let path = std::env::temp_dir().join("type_err.xpl");
+        std::fs::write(&path, tmp).unwrap();
+        let err = run_file(path.to_str().unwrap()).unwrap_err().to_string();
+        assert!(
+            err.contains("Cannot apply '-' to int and string"),
+            "{}",
+            err
+        );
+        // the whole `1 - label` expression, not the first "label" in the file
+        assert!(err.contains(":4:9:"), "{}", err);
+    }
+
+    #[test]
+    fn ints_widen_where_floats_and_decimals_are_declared() {
+        let tmp = "<program name=\"widen\" version=\"1.0\">\
+            <function name=\"half\"><param name=\"x\" type=\"float\"/><body>\
+            <return>x / 2</return></body></function>\
+            <function name=\"main\"><body>\
+            <print>half(3)</print>\
+            <var name=\"total\" type=\"float\"/><assign var=\"total\">7</assign>\
+            <print>total / 2</print>\
+            <var name=\"price\" type=\"decimal\"/><assign var=\"price\">5</assign>\
+            <print>price / 2 == 2.5d</print>\
+            </body></function></program>";
+        let path = std::env::temp_dir().join("widen.xpl");
+        std::fs::write(&path, tmp).unwrap();
+        let outputs = run_file(path.to_str().unwrap()).unwrap();
+        assert_eq!(outputs, vec!["1.5", "3.5", "true"]);
+    }
+
+    #[test]
+    fn check_file_collects_every_problem() {
+        let tmp = "<program name=\"many\" version=\"1.0\">\n<function name=\"main\"><body>\n\
+            <prnt>1</prnt>\n\
+            <if><condition>true</condition><then><print>y</print></then></if>\n\
+            <call function=\"nowhere\"/>\n\
+            <print>later</print><assign var=\"later\">1</assign>\n\
+            </body></function></program>";
+        let path = std::env::temp_dir().join("many_problems.xpl");
+        std::fs::write(&path, tmp).unwrap();
+        let diags = check_file(path.to_str().unwrap());
+        let found: Vec<String> = diags
+            .iter()
+            .map(|d| format!("{} {}: {}", d.span.start, d.severity, d.msg))
+            .collect();
+        assert_eq!(
+            found,
+            [
+                "3:1 error: Unknown element <prnt>",
+                "4:1 error: Missing <else> in <if>",
+                "4:45 error: Undefined variable y",
+                "5:1 error: Undefined function nowhere",
+                "6:8 warning: Variable later may be used before it is assigned",
+            ]
+        );
+        // run_file refuses to start and reports the first of them
+        let err = run_file(path.to_str().unwrap()).unwrap_err().to_string();
+        assert!(err.ends_with(":3:1: Unknown element <prnt>"), "{}", err);
+    }
+
+    #[test]
+    fn problems_carry_stable_codes() {
+        let tmp = "<program name=\"codes\" version=\"1.0\"><function name=\"main\"><body>\
+            <print>unknown</print><print>1 / 0</print></body></function></program>";
+        let path = std::env::temp_dir().join("codes.xpl");
+        std::fs::write(&path, tmp).unwrap();
+        let diag = check_file(path.to_str().unwrap())
+            .into_iter()
+            .next()
+            .unwrap();
+        assert_eq!(diag.code, Code::UndefinedVariable);
+        assert!(
+            diag.to_string()
+                .contains(": error[XPL0001]: Undefined variable unknown")
+        );
+        let tmp = tmp.replace("<print>unknown</print>", "");
+        std::fs::write(&path, tmp).unwrap();
+        let err = run_file(path.to_str().unwrap()).unwrap_err();
+        assert_eq!(err.code().id(), "XPL0013");
+        let missing = run_file("no_such_script.xpl").unwrap_err();
+        assert_eq!(missing.code(), Code::Io);
+    }
+
+    #[test]
+    fn xml_and_include_errors_are_located() {
+        let path = std::env::temp_dir().join("malformed.xpl");
+        std::fs::write(
+            &path,
+            "<program name=\"m\">\n  <function></functon>\n</program>",
+        )
+        .unwrap();
+        let diag = check_file(path.to_str().unwrap())
+            .into_iter()
+            .next()
+            .unwrap();
+        assert_eq!((diag.code, diag.span.start), (Code::Xml, Pos::new(2, 22)));
+        let err = run_file(path.to_str().unwrap()).unwrap_err().to_string();
+        assert!(
+            err.ends_with(":2:22: XML parse error: Unexpected closing tag: functon != function"),
+            "{}",
+            err
+        );
+        let path = std::env::temp_dir().join("bad_include.xpl");
+        let tmp = "<program name=\"i\" version=\"1.0\"\n  include=\"examples/math.xpl, missing_lib.xpl\">\n</program>";
+        std::fs::write(&path, tmp).unwrap();
+        let diag = check_file(path.to_str().unwrap())
+            .into_iter()
+            .next()
+            .unwrap();
+        assert_eq!(diag.file, path.to_str().unwrap());
+        assert_eq!((diag.code, diag.span.start), (Code::Io, Pos::new(2, 31)));
+        assert!(
+            diag.msg
+                .starts_with("Cannot read included file missing_lib.xpl"),
+            "{}",
+            diag.msg
+        );
+    }
+
+    #[test]
+    fn check_file_does_not_run_the_script() {
+        let tmp = "<program name=\"forever\" version=\"1.0\"><function name=\"main\"><body>\
+            <while><condition>true</condition><body><print>1</print></body></while>\
+            </body></function></program>";
+        let path = std::env::temp_dir().join("forever.xpl");
+        std::fs::write(&path, tmp).unwrap();
+        assert!(check_file(path.to_str().unwrap()).is_empty());
+    }
+
+    #[test]
+    fn string_operations() {
+        let tmp = "<program name=\"strings\" version=\"1.0\"><function name=\"main\"><body>\
+            <assign var=\"name\"> upper(\"  ada \") </assign>\
+            <assign var=\"name\"><call function=\"trim\"><param>name</param></call></assign>\
+            <print>\"Hi {name}, {{len}} = {len(name)}\"</print>\
+            <print><concat><param>\"n=\"</param><param>1 + 2</param><param>true</param></concat></print>\
+            <assign var=\"parts\"><call function=\"split\"><param>\"a,b,c\"</param><param>\",\"</param></call></assign>\
+            <print>parts</print>\
+            <print><call function=\"get\"><param>parts</param><param>1</param></call></print>\
+            <print><call function=\"substr\"><param>\"hello\"</param><param>1</param><param>3</param></call></print>\
+            <print><call function=\"replace\"><param>\"a-b-c\"</param><param>\"-\"</param><param>\"+\"</param></call></print>\
+            <print><call function=\"contains\"><param>\"hello\"</param><param>\"ell\"</param></call></print>\
+            <print><call function=\"lower\"><param>\"MiXeD\"</param></call></print>\
+            </body></function></program>";
+        let path = std::env::temp_dir().join("strings.xpl");
+        std::fs::write(&path, tmp).unwrap();
+        let outputs = run_file(path.to_str().unwrap()).unwrap();
+        assert_eq!(
+            outputs,
+            vec![
+                "Hi ADA, {len} = 3",
+                "n=3true",
+                "[a, b, c]",
+                "b",
+                "ell",
+                "a+b+c",
+                "true",
+                "mixed"
+            ]
+        );
+    }
+
+    #[test]
+    fn while_loops_with_break_and_continue() {
+        let tmp = "<program name=\"while\" version=\"1.0\"><function name=\"main\"><body>\
+            <assign var=\"i\">0</assign>\
+            <while><condition>true</condition><body>\
+              <assign var=\"i\">i + 1</assign>\
+              <if><condition>i % 2 == 0</condition><then><continue/></then><else/></if>\
+              <if><condition>i &gt; 7</condition><then><break/></then><else/></if>\
+              <print>i</print>\
+            </body></while>\
+            <loop times=\"5\"><print>\"once\"</print><break/></loop>\
+            </body></function></program>";
+        let path = std::env::temp_dir().join("while.xpl");
+        std::fs::write(&path, tmp).unwrap();
+        let outputs = run_file(path.to_str().unwrap()).unwrap();
+        assert_eq!(outputs, vec!["1", "3", "5", "7", "once"]);
+    }
+
+    #[test]
+    fn break_outside_loop_error() {
+        let tmp = "<program name=\"brk\" version=\"1.0\"><function name=\"main\"><body><if><condition>1</condition><then><break/></then><else/></if></body></function></program>";
+        let path = std::env::temp_dir().join("break_outside.xpl");
+        std::fs::write(&path, tmp).unwrap();
+        let err = run_file(path.to_str().unwrap()).unwrap_err().to_string();
+        assert!(err.contains("<break/> outside of a loop"), "{}", err);
+    }
+
+    #[test]
+    fn counted_for_loops() {
+        let tmp = "<program name=\"for\" version=\"1.0\"><function name=\"main\"><body>\
+            <for var=\"i\" from=\"0\" to=\"10\" step=\"4\"><print>i</print></for>\
+            <for var=\"i\" from=\"3\" to=\"1\" step=\"-1\" inclusive=\"true\"><print>i</print></for>\
+            <for var=\"i\" from=\"5\" to=\"5\"><print>\"never\"</print></for>\
+            <loop var=\"n\" times=\"2\"><print>\"n={n}\"</print></loop>\
+            <loop times=\"0\"><print>\"never\"</print></loop>\
+            </body></function></program>";
+        let path = std::env::temp_dir().join("for.xpl");
+        std::fs::write(&path, tmp).unwrap();
+        let outputs = run_file(path.to_str().unwrap()).unwrap();
+        assert_eq!(outputs, vec!["0", "4", "8", "3", "2", "1", "n=0", "n=1"]);
+    }
+
+    #[test]
+    fn negative_loop_count_error() {
+        let tmp = "<program name=\"neg\" version=\"1.0\"><function name=\"main\"><body><loop times=\"-3\"><print>1</print></loop></body></function></program>";
+        let path = std::env::temp_dir().join("negative_loop.xpl");
+        std::fs::write(&path, tmp).unwrap();
+        let err = run_file(path.to_str().unwrap()).unwrap_err().to_string();
+        assert!(
+            err.contains("Loop count must not be negative, got -3"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn main_takes_arguments_and_returns_the_exit_status() {
+        let tmp = "<program name=\"args\" version=\"1.0\"><function name=\"main\">\
+            <param name=\"n\" type=\"int\"/><param name=\"who\"/><param name=\"verbose\" type=\"bool\"/>\
+            <body><print>\"{who} {n * 2} {verbose}\"</print><print>len(args())</print><return>n - 40</return></body>\
+            </function></program>";
+        let path = std::env::temp_dir().join("main_args.xpl");
+        std::fs::write(&path, tmp).unwrap();
+        let path = path.to_str().unwrap();
+        let args = |a: &[&str]| a.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        let run = run_file_with_args(path, &args(&["42", "ada", "true"])).unwrap();
+        assert_eq!(run.outputs, ["ada 84 true", "3"]);
+        assert_eq!(run.status, 2);
+        let err = run_file_with_args(path, &args(&["42"])).unwrap_err();
+        assert_eq!(err.code(), Code::ArgumentCount);
+        let err = run_file_with_args(path, &args(&["many", "ada", "true"])).unwrap_err();
+        assert_eq!(err.code(), Code::TypeMismatch);
+        assert!(
+            err.to_string()
+                .ends_with("Argument 1 of main expects int, got \"many\""),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn scripts_call_native_functions() {
+        let tmp = "<program name=\"host\" version=\"1.0\"><function name=\"main\"><body>\
+            <print>twice(21)</print>\
+            <print><call function=\"greet\"><param>\"ada\"</param></call></print>\
+            <print>twice(0)</print>\
+            </body></function></program>";
+        let path = std::env::temp_dir().join("natives.xpl");
+        std::fs::write(&path, tmp).unwrap();
+        let path = path.to_str().unwrap();
+        let mut vm = vm::VM::new(path.to_string());
+        vm.register_native(
+            Signature::new("twice").param("n", "int").returns("int"),
+            |args| match args[0] {
+                Value::Int(0) => Err(XplError::native("zero is not allowed")),
+                Value::Int(n) => Ok(Value::Int(n * 2)),
+                _ => unreachable!("checked against the signature"),
+            },
+        );
+        vm.register_native(Signature::new("greet").param("name", "string"), |args| {
+            Ok(Value::Str(format!("hello {}", args[0])))
+        });
+        let unknown = check_file(path);
+        assert_eq!(unknown.first_error().unwrap().code, Code::UndefinedFunction);
+        assert!(check_file_with(path, &vm.native_signatures()).is_empty());
+
+        let capture = vm::Capture::default();
+        vm.set_output(capture.clone());
+        let err = vm.run(&parser::parse_file(path).unwrap()).unwrap_err();
+        assert_eq!(capture.take_lines(), ["42", "hello ada"]);
+        assert_eq!(err.code(), Code::NativeFailed);
+        assert!(
+            err.to_string().ends_with(":1:160: zero is not allowed"),
+            "{}",
+            err
+        );
+
+        let bad = [Signature::new("twice").param("n", "int")];
+        std::fs::write(
+            path,
+            tmp.replace("twice(21)", "twice(\"x\")")
+                .replace("twice(0)", "twice(1, 2)"),
+        )
+        .unwrap();
+        let codes: Vec<Code> = check_file_with(path, &bad).iter().map(|d| d.code).collect();
+        assert!(codes.contains(&Code::TypeMismatch), "{:?}", codes);
+        assert!(codes.contains(&Code::ArgumentCount), "{:?}", codes);
+    }
+
+    #[test]
+    fn hosts_run_snippets_call_functions_and_share_globals() {
+        let dir = std::env::temp_dir().join("xpl_include_path");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("embed_lib.xpl"),
+            "<program name=\"lib\"><function name=\"double\"><param name=\"n\" type=\"int\"/>\
+             <body><return>n * 2</return></body></function></program>",
+        )
+        .unwrap();
+        let src = "<program name=\"rules\" include=\"embed_lib.xpl\">\
+            <function name=\"bump\"><param name=\"by\" type=\"int\"/><body>\
+              <assign var=\"total\">total + double(by)</assign>\
+              <print>\"total is {total}\"</print>\
+              <return>total &gt; limit</return>\
+            </body></function></program>";
+        let capture = vm::Capture::default();
+        let mut vm = vm::VM::builder()
+            .output(capture.clone())
+            .include_path(&dir)
+            .global("total", Value::Int(1))
+            .global("limit", Value::Int(10))
+            .build();
+        let prog = vm.load_str(src, "<rules>").unwrap();
+        assert_eq!(
+            vm.call(&prog, "bump", &[Value::Int(2)]).unwrap(),
+            Value::Bool(false)
+        );
+        vm.set_global("limit", Value::Int(5));
+        assert_eq!(
+            vm.call(&prog, "bump", &[Value::Int(1)]).unwrap(),
+            Value::Bool(true)
+        );
+        assert_eq!(vm.global("total"), Some(&Value::Int(7)));
+        assert_eq!(capture.take_lines(), ["total is 5", "total is 7"]);
+        assert_eq!(
+            vm.call(&prog, "len", &[Value::Str("abc".into())]).unwrap(),
+            Value::Int(3)
+        );
+        let err = vm.call(&prog, "bump", &[]).unwrap_err();
+        assert_eq!(err.code(), Code::ArgumentCount);
+
+        // without the global, the script does not check
+        let diags = vm::VM::builder()
+            .include_path(&dir)
+            .build()
+            .load_str(src, "<rules>");
+        let diags = diags.err().unwrap();
+        assert!(diags.iter().any(|d| d.code == Code::UndefinedVariable));
+        let err = parser::parse_str("<program><function>", "<rules>").unwrap_err();
+        assert!(err.to_string().starts_with("<rules>:1:"), "{}", err);
+    }
+
+    #[test]
+    fn limits_stop_runaway_scripts() {
+        let src = "<program name=\"runaway\">\
+            <function name=\"down\"><param name=\"n\"/><body><return>down(n + 1)</return></body></function>\
+            <function name=\"spin\"><body><while><condition>true</condition><body/></while></body></function>\
+            <function name=\"chatty\"><body><loop times=\"1000\"><print>\"0123456789\"</print></loop></body></function>\
+            <function name=\"nested\"><body><return>1 + (1 + (1 + (1 + (1 + (1 + (1 + (1 + (1 + (1 + (1 + (1 + (1 + (1 + \
+            nested())))))))))))))</return></body></function>\
+            </program>";
+        let limited = |limits: Limits| {
+            let mut vm = vm::VM::builder().limits(limits).build();
+            let prog = vm.load_str(src, "<runaway>").unwrap();
+            move |name: &str| {
+                let args = if name == "down" {
+                    vec![Value::Int(0)]
+                } else {
+                    vec![]
+                };
+                match vm.call(&prog, name, &args) {
+                    Err(XplError::LimitExceeded { limit, line, .. }) => (limit, line),
+                    other => panic!("{} was not stopped: {:?}", name, other),
+                }
+            }
+        };
+        // recursion is bounded by default, well before the stack overflows
+        let mut call = limited(Limits::default());
+        assert_eq!(
+            call("down").0,
+            limits::Limit::CallDepth(limits::DEFAULT_CALL_DEPTH)
+        );
+        // expressions nest through calls, so deep ones stop recursion sooner
+        assert_eq!(
+            call("nested").0,
+            limits::Limit::Nesting(limits::MAX_NESTING)
+        );
+        let mut call = limited(Limits {
+            steps: Some(1000),
+            ..Limits::default()
+        });
+        assert_eq!(call("spin"), (limits::Limit::Steps(1000), 1));
+        let mut call = limited(Limits {
+            loop_iterations: Some(50),
+            output_bytes: Some(100),
+            time: Some(std::time::Duration::from_millis(20)),
+            ..Limits::default()
+        });
+        assert_eq!(call("chatty").0, limits::Limit::OutputBytes(100));
+        assert_eq!(call("spin").0, limits::Limit::LoopIterations(50));
+        let mut call = limited(Limits {
+            time: Some(std::time::Duration::from_millis(20)),
+            ..Limits::default()
+        });
+        assert!(matches!(call("spin").0, limits::Limit::Time(_)));
+    }
+
+    #[test]
+    fn deep_expressions_fail_with_an_error() {
+        let program = |expr: &str| {
+            format!(
+                "<program name=\"deep\"><function name=\"main\"><body>\
+                 <print>{}</print></body></function></program>",
+                expr
+            )
+        };
+        let mut vm = vm::VM::builder().build();
+        let sum = vec!["1"; 20000].join(" + ");
+        let parens = format!("{}1{}", "(".repeat(20000), ")".repeat(20000));
+        let nots = format!("{}true{}", "<not>".repeat(3000), "</not>".repeat(3000));
+        let concat = format!("<concat>{}</concat>", "<param>1</param>".repeat(200));
+        let placeholders = format!("\"{}\"", "{1}".repeat(200));
+        // operator elements and the text inside them nest together
+        let mixed = |n: usize| {
+            let text = format!("{}true", "not ".repeat(n));
+            format!("{}{}{}", "<not>".repeat(60), text, "</not>".repeat(60))
+        };
+        for expr in [sum, parens, nots, concat, placeholders, mixed(40)] {
+            let diags = vm.load_str(&program(&expr), "<deep>").unwrap_err();
+            let msg = diags.iter().map(ToString::to_string).collect::<String>();
+            assert!(msg.contains("XPL0026"), "{}", msg);
+            assert!(msg.contains("nested more than 100 levels deep"), "{}", msg);
+        }
+        vm.load_str(&program(&mixed(39)), "<deep>").unwrap();
+        // the VM also stops on deep expressions it did not parse itself
+        let mut prog = vm.load_str(&program("1"), "<deep>").unwrap();
+        let mut expr = parser::Expr::from(parser::ExprKind::LiteralInt(1));
+        for _ in 0..limits::MAX_NESTING {
+            let one = parser::Expr::from(parser::ExprKind::LiteralInt(1));
+            let kind =
+                parser::ExprKind::BinaryOp(parser::BinOp::Add, Box::new(expr), Box::new(one));
+            expr = parser::Expr::from(kind);
+        }
+        let main = prog.functions.get_mut("main").unwrap();
+        main.body[0].kind = parser::StmtKind::Print(expr);
+        match vm.run(&prog) {
+            Err(XplError::LimitExceeded { limit, .. }) => {
+                assert_eq!(limit, limits::Limit::Nesting(limits::MAX_NESTING))
+            }
+            other => panic!("deep expression was not stopped: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn deep_statements_fail_with_an_error() {
+        let depth = 1000;
+        let ifs = "<if><condition>true</condition><then>\n".repeat(depth);
+        let ends = "</then><else/></if>\n".repeat(depth);
+        let src = format!(
+            "<program name=\"deep\"><function name=\"main\"><body>\
+             {}<print>1</print><break/>{}</body></function></program>",
+            ifs, ends
+        );
+        let vm = vm::VM::builder().build();
+        let diags = vm.load_str(&src, "<deep>").unwrap_err();
+        // the statements past the limit are left out, so nothing else is reported about them
+        let errors: Vec<_> = diags.iter().collect();
+        assert_eq!(errors.len(), 1, "{:?}", errors);
+        assert_eq!(errors[0].code, Code::TooDeep);
+        assert!(
+            errors[0]
+                .msg
+                .contains("Statement is nested more than 50 levels deep"),
+            "{}",
+            errors[0]
+        );
+    }
+
+    /// Run a program of `functions` under the `arithmetic` attribute, empty for the default,
+    /// and return what it printed, one line per print
+    fn run_with(arithmetic: &str, functions: &str) -> Result<String, XplError> {
+        let attr = match arithmetic {
+            "" => String::new(),
+            mode => format!(" arithmetic=\"{}\"", mode),
+        };
+        let src = format!("<program name=\"t\"{}>{}</program>", attr, functions);
+        let capture = vm::Capture::default();
+        let mut vm = vm::VM::builder().output(capture.clone()).build();
+        let prog = vm
+            .load_str(&src, "<test>")
+            .map_err(|d| XplError::from(d.first_error().unwrap().clone()))?;
+        vm.run(&prog)?;
+        Ok(capture.take_lines().join("\n"))
+    }
+
+    #[test]
+    fn integer_overflow_follows_the_program_policy() {
+        let run = |arithmetic: &str, expr: &str| {
+            let main = format!(
+                "<function name=\"main\"><body>\
+                 <assign var=\"min\">-9223372036854775807 - 1</assign>\
+                 <print>{}</print></body></function>",
+                expr
+            );
+            run_with(arithmetic, &main)
+        };
+        let err = run("", "min - 1").unwrap_err().to_string();
+        assert!(
+            err.ends_with(":1:105: Integer overflow in -9223372036854775808 - 1"),
+            "{}",
+            err
+        );
+        assert!(run("", "min / -1").is_err());
+        assert!(run("checked", "-min").is_err());
+        assert_eq!(run("", "min % -1").unwrap(), "0");
+        let err = run("", "-(-9223372036854775808)").unwrap_err();
+        assert_eq!(err.code(), Code::Overflow);
+        assert!(
+            err.to_string()
+                .contains("Integer overflow in -(-9223372036854775808)"),
+            "{}",
+            err
+        );
+        assert_eq!(run("wrapping", "min - 1").unwrap(), "9223372036854775807");
+        assert_eq!(run("wrapping", "min / -1").unwrap(), "-9223372036854775808");
+        assert_eq!(
+            run("wrapping", "-(-9223372036854775808)").unwrap(),
+            "-9223372036854775808"
+        );
+        assert_eq!(
+            run("saturating", "min * 2").unwrap(),
+            "-9223372036854775808"
+        );
+        assert_eq!(run("saturating", "-min").unwrap(), "9223372036854775807");
+        let err = run("loose", "1").unwrap_err();
+        assert_eq!(err.code(), Code::InvalidAttribute);
+    }
+
+    #[test]
+    fn bigint_programs_keep_exact_results_past_64_bits() {
+        let run = |arithmetic: &str, expr: &str| {
+            let functions = format!(
+                "<function name=\"factorial\">\
+                 <param name=\"n\" type=\"int\"/><return type=\"int\"/><body>\
+                 <if><condition>n == 0</condition><then><return>1</return></then>\
+                 <else><return>n * factorial(n - 1)</return></else></if></body></function>\
+                 <function name=\"main\"><body><print>{}</print></body></function>",
+                expr
+            );
+            run_with(arithmetic, &functions)
+        };
+        assert_eq!(
+            run("bigint", "factorial(30)").unwrap(),
+            "265252859812191058636308480000000"
+        );
+        // results that fit again are ordinary ints
+        assert_eq!(
+            run("bigint", "factorial(25) / factorial(23)").unwrap(),
+            "600"
+        );
+        assert_eq!(
+            run(
+                "bigint",
+                "-99999999999999999999 % 7 == -(99999999999999999999 % 7)"
+            )
+            .unwrap(),
+            "true"
+        );
+        let err = run("", "factorial(30)").unwrap_err();
+        assert_eq!(err.code(), Code::Overflow);
+        let err = run("", "99999999999999999999").unwrap_err();
+        assert_eq!(err.code(), Code::Overflow);
+        let err = run("", "int(\"99999999999999999999\")").unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("Integer overflow in int(99999999999999999999)"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn decimals_are_exact_and_convert_explicitly() {
+        let run = |body: &str| {
+            let main = format!("<function name=\"main\"><body>{}</body></function>", body);
+            run_with("", &main)
+        };
+        assert_eq!(
+            run(
+                "<var name=\"total\" type=\"decimal\"/><assign var=\"total\">0</assign>\
+                 <assign var=\"total\">total + 0.10d + 0.20d</assign>\
+                 <print>total</print><print>total == 0.3d</print><print>0.1 + 0.2 == 0.3</print>\
+                 <print>19.99d * 3</print><print>1d / 4</print>"
+            )
+            .unwrap(),
+            "0.30\ntrue\nfalse\n59.97\n0.25"
+        );
+        assert_eq!(
+            run(
+                "<print>int(7.9d)</print><print>int(-2.5)</print><print>float(1.25d)</print>\
+                 <print>decimal(0.1)</print><print>decimal(\" 2.50 \")</print>\
+                 <print>int(\"42\") + 1</print><print>1.5d &lt; 2.0</print>"
+            )
+            .unwrap(),
+            "7\n-2\n1.25\n0.1\n2.50\n43\ntrue"
+        );
+        let err = run("<print>1.5d + 0.5</print>").unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("Cannot apply '+' to decimal and float"),
+            "{}",
+            err
+        );
+        let err =
+            run("<var name=\"x\" type=\"decimal\"/><assign var=\"x\">1.5</assign>").unwrap_err();
+        assert_eq!(err.code(), Code::TypeMismatch);
+        let err = run("<print>1d / 0</print>").unwrap_err();
+        assert_eq!(err.code(), Code::DivisionByZero);
+        let err = run("<print>decimal(\"lots\")</print>").unwrap_err();
+        assert!(
+            err.to_string().contains("decimal cannot parse \"lots\""),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn output_is_written_before_a_runtime_error() {
+        let tmp = "<program name=\"partial\" version=\"1.0\"><function name=\"main\"><body>\
+            <print>\"before\"</print><print>1 / 0</print><print>\"after\"</print>\
+            </body></function></program>";
+        let path = std::env::temp_dir().join("partial_output.xpl");
+        std::fs::write(&path, tmp).unwrap();
+        let capture = vm::Capture::default();
+        let err = run_file_to(path.to_str().unwrap(), &[], capture.clone()).unwrap_err();
+        assert_eq!(err.code(), Code::DivisionByZero);
+        assert_eq!(capture.take_lines(), ["before"]);
+    }
+
+    #[test]
+    fn test_file_runs_test_functions_in_order() {
+        let tmp = "<program name=\"t\" version=\"1.0\">\
+            <function name=\"test_pass\"><body><call function=\"assert\"><param>1 + 1 == 2</param></call></body></function>\
+            <function name=\"helper\"><body><call function=\"assert\"><param>false</param></call></body></function>\
+            <function name=\"test_fail\"><body><call function=\"assert\"><param>1 == 2</param><param>\"no\"</param></call></body></function>\
+            </program>";
+        let path = std::env::temp_dir().join("tests.xpl");
+        std::fs::write(&path, tmp).unwrap();
+        let results = test_file(path.to_str().unwrap()).ok().unwrap();
+        let names: Vec<&str> = results.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(names, ["test_pass", "test_fail"]);
+        assert!(results[0].outcome.is_ok());
+        let err = results[1].outcome.as_ref().unwrap_err();
+        assert_eq!(err.code(), Code::BuiltinFailed);
+        assert!(err.to_string().ends_with("Assertion failed: no"), "{}", err);
+    }
+
+    #[test]
+    fn statements_keep_variables_between_runs() {
+        let mut diags = Diagnostics::new();
+        let mut prog = parser::Program {
+            description: None,
+            functions: Default::default(),
+            arithmetic: Default::default(),
+        };
+        let mut vm = vm::VM::new("<repl>".to_string());
+        let mut run = |text: &str| {
+            let (functions, stmts) = parser::parse_snippet(text, "<repl>", &mut diags).unwrap();
+            for f in functions {
+                prog.functions.insert(f.name.clone(), f);
+            }
+            vm.run_statements(&prog, &stmts)
+        };
+        run("<assign var=\"x\">20</assign>").unwrap();
+        run("<function name=\"inc\"><param name=\"n\"/><body><return>n + 1</return></body></function>").unwrap();
+        assert!(run("<print>nope</print>").is_err());
+        assert_eq!(run("<print>inc(x) * 2</print>").unwrap(), ["42"]);
+    }
 }
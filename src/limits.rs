@@ -0,0 +1,76 @@
+// src/limits.rs
+
+use std::fmt;
+use std::time::Duration;
+
+/// Call depth allowed by default. Each call takes a few kilobytes of native stack on top of
+/// the statements and expressions it runs, so with `MAX_NESTING` this fits in the 2 MiB a
+/// spawned thread gets even in debug builds.
+pub const DEFAULT_CALL_DEPTH: usize = 50;
+
+/// How deeply operators and calls may nest within one expression. The parser rejects
+/// deeper expressions, since every later pass recurses into them.
+pub const MAX_EXPR_DEPTH: usize = 100;
+
+/// How deeply statements such as `<if>` and `<loop>` may nest within one function body.
+/// The parser rejects deeper statements, so no later pass recurses past this.
+pub const MAX_STMT_DEPTH: usize = 50;
+
+/// How deeply statements and expressions may nest while a script runs, counting through
+/// every active call. The VM recurses into both, so this bounds its stack whatever the
+/// limits; it is as deep as a single function may nest them.
+pub const MAX_NESTING: usize = MAX_STMT_DEPTH + MAX_EXPR_DEPTH;
+
+/// Bounds on what a script may do in one run or call, for hosts running untrusted scripts.
+/// `None` leaves a resource unbounded.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limits {
+    /// Statements and expressions evaluated
+    pub steps: Option<u64>,
+    /// Script function calls active at once, counting the function that was started
+    pub call_depth: Option<usize>,
+    /// Iterations of any one loop
+    pub loop_iterations: Option<u64>,
+    /// Bytes printed, counting line breaks
+    pub output_bytes: Option<usize>,
+    /// Time since the run or call started
+    pub time: Option<Duration>,
+}
+
+/// Only the call depth is bounded by default, so deep recursion fails with an error
+/// instead of overflowing the stack of the host
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            steps: None,
+            call_depth: Some(DEFAULT_CALL_DEPTH),
+            loop_iterations: None,
+            output_bytes: None,
+            time: None,
+        }
+    }
+}
+
+/// The limit a script ran into, with its configured value
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Limit {
+    Steps(u64),
+    CallDepth(usize),
+    Nesting(usize),
+    LoopIterations(u64),
+    OutputBytes(usize),
+    Time(Duration),
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Limit::Steps(n) => write!(f, "Step limit of {} exceeded", n),
+            Limit::CallDepth(n) => write!(f, "Call depth limit of {} exceeded", n),
+            Limit::Nesting(n) => write!(f, "Nesting limit of {} exceeded", n),
+            Limit::LoopIterations(n) => write!(f, "Loop iteration limit of {} exceeded", n),
+            Limit::OutputBytes(n) => write!(f, "Output limit of {} bytes exceeded", n),
+            Limit::Time(d) => write!(f, "Time limit of {:?} exceeded", d),
+        }
+    }
+}
@@ -1,18 +1,405 @@
+use std::io::{BufRead, IsTerminal, Write};
+use std::process::exit;
+use xpl::emit::{self, ErrorFormat};
+use xpl::{Diagnostic, Diagnostics, Span, parser, vm};
+
+/// A script failed to load, check or run, or a test failed
+const EXIT_FAILURE: i32 = 1;
+/// The command line itself was wrong
+const EXIT_USAGE: i32 = 2;
+
+const USAGE: &str = "\
+Usage: xpl <command> [options] [args]
+
+Commands:
+  run <file.xpl> [args...]      Check and run a script; `xpl <file.xpl>` does the same
+  check <file.xpl>...           Report problems without running anything
+  fmt [--check] <file.xpl>...   Re-indent scripts in place, or list the ones that need it
+  test <file.xpl or dir>...     Run every test_* function and report the results
+  repl                          Evaluate statements and expressions interactively
+  doc <file.xpl>                Print Markdown documentation of a script's functions
+  ast <file.xpl>                Print the parsed program
+  explain <code>                Describe an error code such as XPL0001
+
+Options:
+  --error-format=<format>  human (default), json or sarif, for run and check
+  --check                  With fmt, change nothing and fail if a file needs formatting
+  -h, --help               Print this help
+  -V, --version            Print the version
+
+Arguments after the script are passed to its main; put them after `--`
+when they start with `-`.
+
+Exit status is the int main returns, or 0; it is 1 when a script has
+errors or a test fails, and 2 when the command line is wrong. A main
+returning 1 or 2 looks the same to the caller, so scripts that need to
+be told apart from these should return other values.";
+
 fn main() {
-    let args: Vec<String> = std::env::args().collect();
-    if args.len() != 2 {
-        eprintln!("Usage: {} <script.xpl>", args[0]);
-        std::process::exit(1);
-    }
-    match xpl::run_file(&args[1]) {
-        Ok(outputs) => {
-            for line in outputs {
-                println!("{}", line);
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    // everything after `--` belongs to the script
+    let script_args = match args.iter().position(|a| a == "--") {
+        Some(i) => {
+            let rest = args.split_off(i + 1);
+            args.pop();
+            rest
+        }
+        None => Vec::new(),
+    };
+    let mut format = None;
+    let mut check_only = false;
+    let mut positional = Vec::new();
+    for arg in args {
+        match arg.as_str() {
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return;
+            }
+            "-V" | "--version" => {
+                println!("xpl {}", env!("CARGO_PKG_VERSION"));
+                return;
+            }
+            "--check" => check_only = true,
+            a if a.starts_with("--error-format=") => {
+                format = Some(
+                    a["--error-format=".len()..]
+                        .parse()
+                        .unwrap_or_else(|e: String| usage_error(&e)),
+                );
             }
+            a if a.starts_with('-') => usage_error(&format!("Unknown option {}", a)),
+            _ => positional.push(arg),
+        }
+    }
+    if positional.is_empty() {
+        usage_error("Missing command");
+    }
+    let command = positional.remove(0);
+    if check_only && command != "fmt" {
+        usage_error("--check only applies to fmt");
+    }
+    let is_script = |c: &str| c == "run" || c.ends_with(".xpl");
+    if !script_args.is_empty() && !is_script(&command) {
+        usage_error("Only run passes arguments to the script");
+    }
+    if format.is_some() && command != "check" && !is_script(&command) {
+        usage_error("--error-format only applies to run and check");
+    }
+    let format = format.unwrap_or(ErrorFormat::Human);
+    let code = match command.as_str() {
+        "run" if positional.is_empty() => usage_error("run needs a script"),
+        "run" => {
+            let path = positional.remove(0);
+            positional.extend(script_args);
+            run(&path, &positional, format)
         }
+        "check" => check(&at_least_one(&command, positional), format),
+        "fmt" => fmt(&at_least_one(&command, positional), check_only),
+        "test" => test(&at_least_one(&command, positional)),
+        "repl" if positional.is_empty() => repl(),
+        "repl" => usage_error("repl takes no arguments"),
+        "doc" => doc(&single(&command, &positional)),
+        "ast" => ast(&single(&command, &positional)),
+        "explain" => explain(&single(&command, &positional)),
+        // scripts could be run directly before there were subcommands
+        path if is_script(path) => {
+            positional.extend(script_args);
+            run(path, &positional, format)
+        }
+        other => usage_error(&format!("Unknown command {}", other)),
+    };
+    exit(code);
+}
+
+fn usage_error(msg: &str) -> ! {
+    eprintln!("error: {}", msg);
+    eprintln!("Run `xpl --help` for usage.");
+    exit(EXIT_USAGE);
+}
+
+/// The one argument a command takes
+fn single(command: &str, args: &[String]) -> String {
+    match args {
+        [arg] => arg.clone(),
+        _ => usage_error(&format!("{} takes exactly one argument", command)),
+    }
+}
+
+fn at_least_one(command: &str, args: Vec<String>) -> Vec<String> {
+    if args.is_empty() {
+        usage_error(&format!("{} needs at least one file", command));
+    }
+    args
+}
+
+fn run(path: &str, args: &[String], format: ErrorFormat) -> i32 {
+    if format != ErrorFormat::Human {
+        return run_reporting(path, args, format);
+    }
+    // report every static problem at once before running anything
+    let diags = xpl::check_file(path);
+    diags.pretty_print();
+    if diags.has_errors() {
+        return EXIT_FAILURE;
+    }
+    match xpl::run_file_to(path, args, std::io::stdout()) {
+        Ok(status) => status,
         Err(e) => {
             e.pretty_print();
-            std::process::exit(1);
+            EXIT_FAILURE
+        }
+    }
+}
+
+/// Like `run`, but write every diagnostic, including a runtime error, as one
+/// machine-readable report at the end so a SARIF log stays a single document
+fn run_reporting(path: &str, args: &[String], format: ErrorFormat) -> i32 {
+    let mut diags = xpl::check_file(path);
+    let mut status = 0;
+    if !diags.has_errors() {
+        match xpl::run_file_to(path, args, std::io::stdout()) {
+            Ok(code) => status = code,
+            Err(e) => diags.push(e.into()),
+        }
+    }
+    report(&diags, format);
+    if diags.has_errors() {
+        EXIT_FAILURE
+    } else {
+        status
+    }
+}
+
+fn report(diags: &Diagnostics, format: ErrorFormat) {
+    match format {
+        ErrorFormat::Human => diags.pretty_print(),
+        ErrorFormat::Json => eprint!("{}", emit::to_json(diags)),
+        ErrorFormat::Sarif => eprintln!("{:#}", emit::to_sarif(diags)),
+    }
+}
+
+fn check(paths: &[String], format: ErrorFormat) -> i32 {
+    let mut all = Diagnostics::new();
+    for path in paths {
+        all.extend(xpl::check_file(path));
+    }
+    report(&all, format);
+    if all.has_errors() { EXIT_FAILURE } else { 0 }
+}
+
+fn fmt(paths: &[String], check_only: bool) -> i32 {
+    let mut code = 0;
+    for path in paths {
+        let formatted = match xpl::fmt::format_file(path) {
+            Ok(formatted) => formatted,
+            Err(e) => {
+                e.pretty_print();
+                code = EXIT_FAILURE;
+                continue;
+            }
+        };
+        if std::fs::read_to_string(path).is_ok_and(|src| src == formatted) {
+            continue;
+        }
+        if check_only {
+            println!("{}", path);
+            code = EXIT_FAILURE;
+        } else if let Err(e) = std::fs::write(path, formatted) {
+            eprintln!("error: cannot write {}: {}", path, e);
+            code = EXIT_FAILURE;
+        }
+    }
+    code
+}
+
+fn test(paths: &[String]) -> i32 {
+    let mut files = Vec::new();
+    for path in paths {
+        collect_scripts(std::path::Path::new(path), &mut files);
+    }
+    let (mut passed, mut failed) = (0, 0);
+    let mut failures = Vec::new();
+    for file in &files {
+        let results = match xpl::test_file(file) {
+            Ok(results) => results,
+            Err(diags) => {
+                diags.pretty_print();
+                failed += 1;
+                continue;
+            }
+        };
+        if results.is_empty() {
+            continue;
+        }
+        println!("running {} tests in {}", results.len(), file);
+        for result in results {
+            match result.outcome {
+                Ok(_) => {
+                    println!("test {} ... ok", result.name);
+                    passed += 1;
+                }
+                Err(e) => {
+                    println!("test {} ... FAILED", result.name);
+                    failures.push(e);
+                    failed += 1;
+                }
+            }
+        }
+    }
+    if !failures.is_empty() {
+        println!("\nfailures:");
+        for e in &failures {
+            e.pretty_print();
+        }
+    }
+    let status = if failed == 0 { "ok" } else { "FAILED" };
+    println!(
+        "\ntest result: {}. {} passed; {} failed",
+        status, passed, failed
+    );
+    if failed == 0 { 0 } else { EXIT_FAILURE }
+}
+
+/// The path itself if it is a file, or every `.xpl` file below it if it is a directory
+fn collect_scripts(path: &std::path::Path, out: &mut Vec<String>) {
+    let Ok(entries) = std::fs::read_dir(path) else {
+        out.push(path.to_string_lossy().into_owned());
+        return;
+    };
+    let mut entries: Vec<_> = entries.filter_map(Result::ok).map(|e| e.path()).collect();
+    entries.sort();
+    for entry in entries {
+        if entry.is_dir() {
+            collect_scripts(&entry, out);
+        } else if entry.extension().is_some_and(|ext| ext == "xpl") {
+            out.push(entry.to_string_lossy().into_owned());
+        }
+    }
+}
+
+/// Read statements, `<function>` definitions and bare expressions from stdin and run them,
+/// keeping variables and functions between inputs
+fn repl() -> i32 {
+    let interactive = std::io::stdin().is_terminal();
+    let prompt = |text: &str| {
+        if interactive {
+            print!("{}", text);
+            let _ = std::io::stdout().flush();
+        }
+    };
+    if interactive {
+        println!(
+            "xpl {} REPL; enter statements or expressions, :quit to leave",
+            env!("CARGO_PKG_VERSION")
+        );
+    }
+    let mut prog = parser::Program {
+        description: None,
+        functions: Default::default(),
+        arithmetic: Default::default(),
+    };
+    let mut vm = vm::VM::new("<repl>".to_string());
+    vm.set_output(std::io::stdout());
+    let mut input = String::new();
+    prompt(">>> ");
+    for line in std::io::stdin().lock().lines() {
+        let Ok(line) = line else { break };
+        input.push_str(&line);
+        input.push('\n');
+        // keep reading while an element or string is still open
+        if xpl::fmt::depth(&input) > 0 {
+            prompt("... ");
+            continue;
+        }
+        let text = std::mem::take(&mut input);
+        let text = text.trim();
+        if text == ":quit" || text == ":q" {
+            return 0;
+        }
+        if !text.is_empty() {
+            eval(text, &mut prog, &mut vm);
+        }
+        prompt(">>> ");
+    }
+    0
+}
+
+fn eval(text: &str, prog: &mut parser::Program, vm: &mut vm::VM) {
+    // a bare expression prints its value
+    let text = if text.starts_with('<') {
+        text.to_string()
+    } else {
+        format!("<print>{}</print>", text)
+    };
+    let mut diags = Diagnostics::new();
+    let (functions, stmts) = match parser::parse_snippet(&text, "<repl>", &mut diags) {
+        Ok(parsed) => parsed,
+        Err(e) => return show(e.into()),
+    };
+    if diags.has_errors() {
+        return diags.into_iter().for_each(show);
+    }
+    for func in functions {
+        prog.functions.insert(func.name.clone(), func);
+    }
+    if let Err(e) = vm.run_statements(prog, &stmts) {
+        show(e.into());
+    }
+}
+
+/// Print a problem in REPL input; positions are left out as they count the wrapping
+/// added around the input rather than what was typed
+fn show(mut diag: Diagnostic) {
+    diag.span = Span::default();
+    diag.pretty_print();
+}
+
+/// Load a script for the commands that look at it without checking or running it
+fn parse(path: &str) -> Option<parser::Program> {
+    let mut diags = Diagnostics::new();
+    match parser::parse_file_with(path, &mut diags) {
+        Ok(prog) => Some(prog),
+        Err(e) => {
+            e.pretty_print();
+            None
+        }
+    }
+}
+
+fn doc(path: &str) -> i32 {
+    let Some(prog) = parse(path) else {
+        return EXIT_FAILURE;
+    };
+    print!("{}", xpl::doc::render_markdown(&prog, path));
+    0
+}
+
+fn ast(path: &str) -> i32 {
+    let Some(prog) = parse(path) else {
+        return EXIT_FAILURE;
+    };
+    if let Some(desc) = &prog.description {
+        println!("description: {:?}", desc);
+    }
+    let mut funcs: Vec<&parser::Function> = prog.functions.values().collect();
+    funcs.sort_by(|a, b| (&a.file, a.span.start).cmp(&(&b.file, b.span.start)));
+    for func in funcs {
+        println!("{:#?}", func);
+    }
+    0
+}
+
+/// Print the long explanation of an error code such as `XPL0001`
+fn explain(id: &str) -> i32 {
+    match xpl::codes::lookup(id) {
+        Some(info) => {
+            println!("{}: {}\n", info.id, info.title);
+            println!("{}", info.text);
+            0
+        }
+        None => {
+            eprintln!("Unknown error code {}", id);
+            EXIT_FAILURE
         }
     }
 }
@@ -0,0 +1,129 @@
+// src/native.rs
+
+use crate::callee::Callee;
+use crate::error::XplError;
+use crate::value::Value;
+use serde::{Deserialize, Serialize};
+
+/// Implementation of a native function, called with arguments already checked against
+/// its signature
+pub type NativeFn = Box<dyn Fn(&[Value]) -> Result<Value, XplError>>;
+
+/// Name of the file the language server reads native signatures from, looked up next to
+/// a script and then in each directory above it
+pub const SIGNATURES_FILE: &str = "xpl-natives.json";
+
+/// Declared shape of a function the host application provides. Checking a script only
+/// needs this part, so it can be written to JSON for the language server.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Signature {
+    pub name: String,
+    #[serde(default)]
+    pub params: Vec<NativeParam>,
+    /// Type name of the result, such as `int`; any value when left out
+    #[serde(rename = "returns", default, skip_serializing_if = "Option::is_none")]
+    pub ret_type: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NativeParam {
+    pub name: String,
+    /// Type name such as `int`; any value when left out
+    #[serde(rename = "type", default, skip_serializing_if = "Option::is_none")]
+    pub ptype: Option<String>,
+}
+
+impl Signature {
+    /// A signature with no params and no declared result
+    pub fn new(name: &str) -> Self {
+        Signature {
+            name: name.to_string(),
+            params: Vec::new(),
+            ret_type: None,
+            description: None,
+        }
+    }
+
+    /// Add a param of type `ty`, a name as written in `type` attributes such as `int` or `any`
+    pub fn param(mut self, name: &str, ty: &str) -> Self {
+        self.params.push(NativeParam {
+            name: name.to_string(),
+            ptype: Some(ty.to_string()),
+        });
+        self
+    }
+
+    /// Declare the type of the result
+    pub fn returns(mut self, ty: &str) -> Self {
+        self.ret_type = Some(ty.to_string());
+        self
+    }
+
+    /// Text shown with the signature in editors
+    pub fn describe(mut self, text: &str) -> Self {
+        self.description = Some(text.to_string());
+        self
+    }
+
+    /// `name(a: int, b) -> int`, leaving out types that are not declared
+    pub fn label(&self) -> String {
+        Callee::Native(self).label()
+    }
+
+    /// Check a call's arguments against the declared params, widening ints passed for
+    /// floats or decimals
+    pub(crate) fn bind(&self, mut args: Vec<Value>) -> Result<Vec<Value>, String> {
+        for (p, arg) in self.params.iter().zip(args.iter_mut()) {
+            let ty = p.ptype.as_deref().unwrap_or("any");
+            *arg = std::mem::replace(arg, Value::Null).widen(ty);
+            if ty != "any" && ty != arg.type_name() {
+                return Err(format!(
+                    "Argument {} of {} expects {}, got {}",
+                    p.name,
+                    self.name,
+                    ty,
+                    arg.type_name()
+                ));
+            }
+        }
+        Ok(args)
+    }
+}
+
+/// Signatures declared in the nearest signatures file above the script at `path`,
+/// or none when there is no such file
+pub fn signatures_for(path: &str) -> Result<Vec<Signature>, String> {
+    let path = std::path::Path::new(path);
+    for dir in path.ancestors().skip(1) {
+        let file = dir.join(SIGNATURES_FILE);
+        if let Ok(text) = std::fs::read_to_string(&file) {
+            return serde_json::from_str(&text).map_err(|e| format!("{}: {}", file.display(), e));
+        }
+    }
+    Ok(Vec::new())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signatures_check_arguments_and_round_trip_through_json() {
+        let sig = Signature::new("scale")
+            .param("x", "float")
+            .param("label", "string")
+            .returns("float");
+        assert_eq!(sig.label(), "scale(x: float, label: string) -> float");
+        let args = sig.bind(vec![Value::Int(2), Value::Str("a".into())]);
+        assert_eq!(args.unwrap()[0], Value::Float(2.0));
+        let err = sig
+            .bind(vec![Value::Int(2), Value::Bool(true)])
+            .unwrap_err();
+        assert_eq!(err, "Argument label of scale expects string, got bool");
+        let json = serde_json::to_string(&sig).unwrap();
+        assert!(json.contains("\"type\":\"float\""), "{}", json);
+        assert_eq!(serde_json::from_str::<Signature>(&json).unwrap(), sig);
+    }
+}
@@ -1,14 +1,40 @@
 // src/parser.rs
 
-use crate::error::XplError;
+use crate::codes::Code;
+use crate::diagnostics::Diagnostics;
+use crate::dom::{self, Element, Text, XMLNode};
+use crate::error::{IncludeSite, XplError};
+use crate::expr::{self, Piece};
+use crate::limits::{MAX_EXPR_DEPTH, MAX_STMT_DEPTH};
+use crate::span::{Pos, Span};
+use num_bigint::BigInt;
+use rust_decimal::Decimal;
+use std::cell::RefCell;
 use std::collections::HashMap;
-use std::fs::File;
-use xmltree::{Element, XMLNode};
+use std::path::PathBuf;
 
 #[derive(Debug, Clone)]
 pub struct Program {
     pub description: Option<String>,
     pub functions: HashMap<String, Function>,
+    /// What integer operations do on overflow, from the `arithmetic` attribute of `<program>`
+    pub arithmetic: Arithmetic,
+}
+
+/// Overflow policy for integer arithmetic. It is the same in debug and release builds and
+/// applies to the whole program, including functions from included libraries.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Arithmetic {
+    /// Overflow is a runtime error
+    #[default]
+    Checked,
+    /// Results wrap around at the bounds of a 64-bit integer
+    Wrapping,
+    /// Results stop at the smallest or largest 64-bit integer
+    Saturating,
+    /// Results that do not fit in 64 bits continue with arbitrary precision, and so may
+    /// integer literals
+    Bigint,
 }
 
 #[derive(Debug, Clone)]
@@ -16,6 +42,7 @@ pub struct Param {
     pub name: String,
     pub ptype: Option<String>,
     pub description: Option<String>,
+    pub span: Span,
 }
 
 #[derive(Debug, Clone)]
@@ -23,11 +50,27 @@ pub struct Function {
     pub name: String,
     pub description: Option<String>,
     pub params: Vec<Param>,
+    /// Declared return type from `<return type="..."/>`
+    pub ret_type: Option<String>,
     pub body: Vec<Stmt>,
+    /// File the function was defined in (differs from the script for included libraries)
+    pub file: String,
+    pub span: Span,
+}
+
+/// A statement with the span of the element it was written as
+#[derive(Debug, Clone)]
+pub struct Stmt {
+    pub kind: StmtKind,
+    pub span: Span,
 }
 
 #[derive(Debug, Clone, PartialEq)]
-pub enum Stmt {
+pub enum StmtKind {
+    Var {
+        name: String,
+        vtype: Option<String>,
+    },
     Assign {
         var: String,
         expr: Expr,
@@ -41,9 +84,26 @@ pub enum Stmt {
     Return(Expr),
     Call(String, Vec<Expr>),
     Loop {
+        /// Optional variable exposing the 0-based iteration number
+        var: Option<String>,
         count: Expr,
         body: Vec<Stmt>,
     },
+    For {
+        var: String,
+        from: Expr,
+        to: Expr,
+        step: Expr,
+        /// Whether `to` itself is part of the range
+        inclusive: bool,
+        body: Vec<Stmt>,
+    },
+    While {
+        cond: Expr,
+        body: Vec<Stmt>,
+    },
+    Break,
+    Continue,
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -53,380 +113,862 @@ pub enum BinOp {
     Multiply,
     Divide,
     Modulus,
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    And,
+    Or,
+}
+
+impl std::fmt::Display for BinOp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let sym = match self {
+            BinOp::Add => "+",
+            BinOp::Subtract => "-",
+            BinOp::Multiply => "*",
+            BinOp::Divide => "/",
+            BinOp::Modulus => "%",
+            BinOp::Equal => "==",
+            BinOp::NotEqual => "!=",
+            BinOp::Less => "<",
+            BinOp::LessEqual => "<=",
+            BinOp::Greater => ">",
+            BinOp::GreaterEqual => ">=",
+            BinOp::And => "and",
+            BinOp::Or => "or",
+        };
+        write!(f, "{}", sym)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum UnOp {
+    Negate,
+    Not,
+}
+
+/// An expression with the span of the text or element it was parsed from
+#[derive(Debug, Clone)]
+pub struct Expr {
+    pub kind: ExprKind,
+    pub span: Span,
 }
 
 #[derive(Debug, Clone, PartialEq)]
-pub enum Expr {
+pub enum ExprKind {
     LiteralInt(i64),
+    /// An integer literal too large for 64 bits, only allowed in `bigint` programs
+    LiteralBigInt(BigInt),
+    LiteralFloat(f64),
+    /// A number written with a `d` suffix, such as `19.99d`
+    LiteralDecimal(Decimal),
     LiteralStr(String),
+    LiteralBool(bool),
+    LiteralNull,
     VarRef(String),
     Call(String, Vec<Expr>),
     BinaryOp(BinOp, Box<Expr>, Box<Expr>),
+    UnaryOp(UnOp, Box<Expr>),
+}
+
+impl Stmt {
+    pub fn new(kind: StmtKind, span: Span) -> Self {
+        Stmt { kind, span }
+    }
+}
+
+impl Expr {
+    pub fn new(kind: ExprKind, span: Span) -> Self {
+        Expr { kind, span }
+    }
+}
+
+/// A node built outside of any source file, with an unknown span
+impl From<ExprKind> for Expr {
+    fn from(kind: ExprKind) -> Self {
+        Expr::new(kind, Span::default())
+    }
+}
+
+// Nodes compare by structure alone, so the same code written elsewhere is equal
+impl PartialEq for Stmt {
+    fn eq(&self, other: &Self) -> bool {
+        self.kind == other.kind
+    }
+}
+
+impl PartialEq for Expr {
+    fn eq(&self, other: &Self) -> bool {
+        self.kind == other.kind
+    }
+}
+
+/// The file being parsed, kept around for error locations
+struct Source<'a> {
+    path: &'a str,
+    /// Where problems go once the statement they occurred in has been skipped
+    diags: RefCell<&'a mut Diagnostics>,
+}
+
+impl Source<'_> {
+    fn report(&self, err: XplError) {
+        self.diags.borrow_mut().push(err.into());
+    }
 }
 
-/// Parse an XPL file into a Program AST
+/// Parse an XPL file into a Program AST, failing on the first problem
 pub fn parse_file(path: &str) -> Result<Program, XplError> {
-    let file = File::open(path).map_err(|e| XplError::Io {
+    let mut diags = Diagnostics::new();
+    let prog = parse_file_with(path, &mut diags)?;
+    match diags.into_iter().next() {
+        Some(diag) => Err(diag.into()),
+        None => Ok(prog),
+    }
+}
+
+/// Parse an XPL file, reporting each invalid statement to `diags` and leaving it out of the AST.
+/// Only files that cannot be read or are not well-formed XML fail outright.
+pub fn parse_file_with(path: &str, diags: &mut Diagnostics) -> Result<Program, XplError> {
+    parse_file_in(path, &[], diags)
+}
+
+/// Like `parse_file_with`, also looking for includes in `include_paths`
+fn parse_file_in(
+    path: &str,
+    include_paths: &[PathBuf],
+    diags: &mut Diagnostics,
+) -> Result<Program, XplError> {
+    let text = std::fs::read_to_string(path).map_err(|e| XplError::Io {
         source: e,
         file: path.to_string(),
+        included_from: None,
     })?;
-    let root = Element::parse(file).map_err(|e| XplError::Xml {
-        source: e,
-        file: path.to_string(),
+    parse_str_with(&text, path, include_paths, diags)
+}
+
+/// Parse source held in memory, failing on the first problem. `name` stands in for the
+/// file name in error locations and is where includes are looked up from.
+pub fn parse_str(text: &str, name: &str) -> Result<Program, XplError> {
+    let mut diags = Diagnostics::new();
+    let prog = parse_str_with(text, name, &[], &mut diags)?;
+    match diags.into_iter().next() {
+        Some(diag) => Err(diag.into()),
+        None => Ok(prog),
+    }
+}
+
+/// Like `parse_file_with` for source held in memory under the name `name`. Includes are
+/// looked up next to `name`, then in each of `include_paths`, then in the working directory.
+pub fn parse_str_with(
+    text: &str,
+    name: &str,
+    include_paths: &[PathBuf],
+    diags: &mut Diagnostics,
+) -> Result<Program, XplError> {
+    let root = dom::parse(text).map_err(|e| {
+        let pos = dom::error_pos(&e);
+        XplError::Xml {
+            source: e,
+            file: name.to_string(),
+            line: pos.line,
+            col: pos.col,
+        }
     })?;
     let mut functions = HashMap::new();
     // optional program-level description
     let prog_desc = root
         .get_child("description")
-        .and_then(|d| Some(d.get_text().unwrap_or_default().trim().to_string()));
+        .map(|d| d.text().trim().to_string());
     // Process include only for program roots (to load libs)
-    if root.name == "program" {
-        if let Some(include_list) = root.attributes.get("include") {
-            let script_dir = std::path::Path::new(path)
-                .parent()
-                .unwrap_or_else(|| std::path::Path::new("."));
-            for inc in include_list.split(',').map(|s| s.trim()) {
-                // try script-relative first, then workspace-relative
-                let rel_path = script_dir.join(inc);
-                let inc_path = if rel_path.exists() {
-                    rel_path
-                } else {
-                    std::path::Path::new(inc).to_path_buf()
-                };
-                let included = parse_file(inc_path.to_str().unwrap())?;
-                functions.extend(included.functions);
-            }
+    if root.name == "program"
+        && let Some(include_list) = root.attributes.get("include")
+    {
+        let script_dir = std::path::Path::new(name)
+            .parent()
+            .unwrap_or_else(|| std::path::Path::new("."));
+        let mut offset = 0;
+        for entry in include_list.text.split(',') {
+            let inc = entry.trim();
+            let start = include_list.pos_at(offset + entry.find(inc).unwrap_or(0));
+            offset += entry.len() + 1;
+            // try script-relative first, then the include paths, then workspace-relative
+            let inc_path = std::iter::once(script_dir)
+                .chain(include_paths.iter().map(PathBuf::as_path))
+                .map(|dir| dir.join(inc))
+                .find(|p| p.exists())
+                .unwrap_or_else(|| PathBuf::from(inc));
+            let included = parse_file_in(inc_path.to_str().unwrap(), include_paths, diags)
+                .map_err(|err| match err {
+                    XplError::Io {
+                        source,
+                        file,
+                        included_from: None,
+                    } => XplError::Io {
+                        source,
+                        file,
+                        included_from: Some(IncludeSite {
+                            file: name.to_string(),
+                            line: start.line,
+                            col: start.col,
+                        }),
+                    },
+                    err => err,
+                })?;
+            functions.extend(included.functions);
         }
     }
-    for node in &root.children {
-        if let XMLNode::Element(elem) = node {
-            if elem.name == "function" {
-                // optional function-level description
-                let func_desc = elem
-                    .get_child("description")
-                    .and_then(|d| Some(d.get_text().unwrap_or_default().trim().to_string()));
-                let name = elem.attributes.get("name").cloned().unwrap_or_default();
-                // collect parameters with optional type and description
-                let mut params = Vec::new();
-                for c in &elem.children {
-                    if let XMLNode::Element(e) = c {
-                        if e.name == "param" {
-                            let name = e.attributes.get("name").cloned().unwrap_or_default();
-                            let ptype = e.attributes.get("type").cloned();
-                            // optional description child
-                            let desc = e.get_child("description").and_then(|d| {
-                                Some(d.get_text().unwrap_or_default().trim().to_string())
-                            });
-                            params.push(Param {
-                                name,
-                                ptype,
-                                description: desc,
-                            });
-                        }
-                    }
-                }
-                let mut body = Vec::new();
-                if let Some(body_elem) = elem.get_child("body") {
-                    for stmt_node in &body_elem.children {
-                        if let XMLNode::Element(stmt_elem) = stmt_node {
-                            match stmt_elem.name.as_str() {
-                                "loop" => {
-                                    // parse loop count
-                                    let times_str = stmt_elem
-                                        .attributes
-                                        .get("times")
-                                        .cloned()
-                                        .unwrap_or_else(|| "0".into());
-                                    let count_expr = parse_text_expr(&times_str);
-                                    // parse loop body statements
-                                    let mut loop_body = Vec::new();
-                                    for loop_node in &stmt_elem.children {
-                                        if let XMLNode::Element(e) = loop_node {
-                                            match e.name.as_str() {
-                                                "print" => {
-                                                    let txt = e
-                                                        .get_text()
-                                                        .unwrap_or_default()
-                                                        .trim()
-                                                        .to_string();
-                                                    let expr = if txt.starts_with('"')
-                                                        && txt.ends_with('"')
-                                                    {
-                                                        Expr::LiteralStr(
-                                                            txt.trim_matches('"').to_string(),
-                                                        )
-                                                    } else if let Ok(i) = txt.parse::<i64>() {
-                                                        Expr::LiteralInt(i)
-                                                    } else {
-                                                        Expr::VarRef(txt)
-                                                    };
-                                                    loop_body.push(Stmt::Print(expr));
-                                                }
-                                                "assign" => {
-                                                    let var = e
-                                                        .attributes
-                                                        .get("var")
-                                                        .cloned()
-                                                        .unwrap_or_default();
-                                                    if let Some(XMLNode::Element(expr_elem)) =
-                                                        e.children.get(0)
-                                                    {
-                                                        let expr = parse_expr(expr_elem)?;
-                                                        loop_body.push(Stmt::Assign { var, expr });
-                                                    }
-                                                }
-                                                "call" => {
-                                                    let expr = parse_expr(e)?;
-                                                    if let Expr::Call(name, args) = expr {
-                                                        loop_body.push(Stmt::Call(name, args));
-                                                    }
-                                                }
-                                                _ => {}
-                                            }
-                                        }
-                                    }
-                                    body.push(Stmt::Loop {
-                                        count: count_expr,
-                                        body: loop_body,
-                                    });
-                                }
-                                "call" => {
-                                    // standalone call statement
-                                    let expr = parse_expr(stmt_elem)?;
-                                    if let Expr::Call(name, args) = expr {
-                                        body.push(Stmt::Call(name, args));
-                                    }
-                                }
-                                "return" => {
-                                    let expr = if let Some(XMLNode::Element(e)) =
-                                        stmt_elem.children.get(0)
-                                    {
-                                        parse_expr(e)?
-                                    } else {
-                                        let txt = stmt_elem.get_text().unwrap_or_default();
-                                        parse_text_expr(&txt)
-                                    };
-                                    body.push(Stmt::Return(expr));
-                                }
-                                "if" => {
-                                    let cond_elem =
-                                        stmt_elem.get_child("condition").ok_or_else(|| {
-                                            XplError::Semantic {
-                                                msg: "Missing condition".to_string(),
-                                                file: path.to_string(),
-                                                line: 0,
-                                                col: 0,
-                                            }
-                                        })?;
-                                    // either inner element or text
-                                    let cond_expr = if let Some(XMLNode::Element(e)) =
-                                        cond_elem.children.get(0)
-                                    {
-                                        parse_expr(e)?
-                                    } else {
-                                        let txt = cond_elem
-                                            .get_text()
-                                            .unwrap_or_default()
-                                            .trim()
-                                            .to_string();
-                                        if let Ok(i) = txt.parse::<i64>() {
-                                            Expr::LiteralInt(i)
-                                        } else {
-                                            Expr::VarRef(txt)
-                                        }
-                                    };
-                                    // then block
-                                    let then_elem =
-                                        stmt_elem.get_child("then").ok_or_else(|| {
-                                            XplError::Semantic {
-                                                msg: "Missing then block".to_string(),
-                                                file: path.to_string(),
-                                                line: 0,
-                                                col: 0,
-                                            }
-                                        })?;
-                                    let mut then_body = Vec::new();
-                                    for then_node in &then_elem.children {
-                                        if let XMLNode::Element(e) = then_node {
-                                            if e.name == "print" {
-                                                let txt = e
-                                                    .get_text()
-                                                    .unwrap_or_default()
-                                                    .trim()
-                                                    .to_string();
-                                                let expr =
-                                                    if txt.starts_with('"') && txt.ends_with('"') {
-                                                        Expr::LiteralStr(
-                                                            txt.trim_matches('"').to_string(),
-                                                        )
-                                                    } else if let Ok(i) = txt.parse::<i64>() {
-                                                        Expr::LiteralInt(i)
-                                                    } else {
-                                                        Expr::VarRef(txt)
-                                                    };
-                                                then_body.push(Stmt::Print(expr));
-                                            }
-                                        }
-                                    }
-                                    // else block
-                                    let else_elem =
-                                        stmt_elem.get_child("else").ok_or_else(|| {
-                                            XplError::Semantic {
-                                                msg: "Missing else block".to_string(),
-                                                file: path.to_string(),
-                                                line: 0,
-                                                col: 0,
-                                            }
-                                        })?;
-                                    let mut else_body = Vec::new();
-                                    for else_node in &else_elem.children {
-                                        if let XMLNode::Element(e) = else_node {
-                                            if e.name == "print" {
-                                                let txt = e
-                                                    .get_text()
-                                                    .unwrap_or_default()
-                                                    .trim()
-                                                    .to_string();
-                                                let expr =
-                                                    if txt.starts_with('"') && txt.ends_with('"') {
-                                                        Expr::LiteralStr(
-                                                            txt.trim_matches('"').to_string(),
-                                                        )
-                                                    } else if let Ok(i) = txt.parse::<i64>() {
-                                                        Expr::LiteralInt(i)
-                                                    } else {
-                                                        Expr::VarRef(txt)
-                                                    };
-                                                else_body.push(Stmt::Print(expr));
-                                            }
-                                        }
-                                    }
-                                    body.push(Stmt::If {
-                                        cond: cond_expr,
-                                        then_body,
-                                        else_body,
-                                    });
-                                }
-                                "assign" => {
-                                    let var = stmt_elem
-                                        .attributes
-                                        .get("var")
-                                        .cloned()
-                                        .unwrap_or_default();
-                                    if let Some(XMLNode::Element(expr_elem)) =
-                                        stmt_elem.children.get(0)
-                                    {
-                                        let expr = parse_expr(expr_elem)?;
-                                        body.push(Stmt::Assign { var, expr });
-                                    }
-                                }
-                                "print" => {
-                                    let txt =
-                                        stmt_elem.get_text().unwrap_or_default().trim().to_string();
-                                    let expr = if txt.starts_with('"') && txt.ends_with('"') {
-                                        Expr::LiteralStr(txt.trim_matches('"').to_string())
-                                    } else if let Ok(i) = txt.parse::<i64>() {
-                                        Expr::LiteralInt(i)
-                                    } else {
-                                        Expr::VarRef(txt)
-                                    };
-                                    body.push(Stmt::Print(expr));
-                                }
-                                _ => {}
-                            }
-                        }
-                    }
-                }
-                functions.insert(
-                    name.clone(),
-                    Function {
-                        name,
-                        description: func_desc,
-                        params,
-                        body,
-                    },
-                );
+    let src = Source {
+        path: name,
+        diags: RefCell::new(diags),
+    };
+    let arithmetic = match root.attributes.get("arithmetic") {
+        Some(attr) if root.name == "program" => match attr.text.as_str() {
+            "checked" => Arithmetic::Checked,
+            "wrapping" => Arithmetic::Wrapping,
+            "saturating" => Arithmetic::Saturating,
+            "bigint" => Arithmetic::Bigint,
+            other => {
+                src.report(error(
+                    &src,
+                    attr.span_at(0, other.len()),
+                    Code::InvalidAttribute,
+                    format!(
+                        "arithmetic must be checked, wrapping, saturating or bigint, got {}",
+                        other
+                    ),
+                ));
+                Arithmetic::Checked
             }
+        },
+        _ => Arithmetic::Checked,
+    };
+    for node in &root.children {
+        if let XMLNode::Element(elem) = node
+            && elem.name == "function"
+        {
+            let func = parse_function(elem, &src);
+            functions.insert(func.name.clone(), func);
         }
     }
     Ok(Program {
         description: prog_desc,
         functions,
+        arithmetic,
     })
 }
 
-/// Parse a simple text expression, supporting infix ops
-fn parse_text_expr(txt: &str) -> Expr {
-    let t = txt.trim();
-    // infix pattern
-    let parts: Vec<&str> = t.split_whitespace().collect();
-    if parts.len() == 3 {
-        let left = parse_text_expr(parts[0]);
-        let right = parse_text_expr(parts[2]);
-        let op = match parts[1] {
-            "+" => BinOp::Add,
-            "-" => BinOp::Subtract,
-            "*" => BinOp::Multiply,
-            "/" => BinOp::Divide,
-            "%" => BinOp::Modulus,
-            _ => return Expr::VarRef(t.to_string()),
-        };
-        return Expr::BinaryOp(op, Box::new(left), Box::new(right));
-    }
-    if t.starts_with('"') && t.ends_with('"') {
-        Expr::LiteralStr(t.trim_matches('"').to_string())
-    } else if let Ok(i) = t.parse::<i64>() {
-        Expr::LiteralInt(i)
-    } else {
-        Expr::VarRef(t.to_string())
-    }
-}
-
-fn parse_expr(elem: &Element) -> Result<Expr, XplError> {
-    // Only handle explicit <call> elements
-    if elem.name == "call" {
-        let func = elem.attributes.get("function").cloned().unwrap_or_default();
-        let args = elem
-            .children
-            .iter()
-            .filter_map(|node| {
-                if let XMLNode::Element(e) = node {
-                    if e.name == "param" { Some(e) } else { None }
-                } else {
-                    None
+/// Parse statements and `<function>` definitions that are not wrapped in a `<program>`,
+/// such as input typed at the REPL. Invalid statements are reported to `diags` and left out.
+pub fn parse_snippet(
+    text: &str,
+    file: &str,
+    diags: &mut Diagnostics,
+) -> Result<(Vec<Function>, Vec<Stmt>), XplError> {
+    let wrapped = format!("<snippet>{}</snippet>", text);
+    let root = dom::parse(&wrapped).map_err(|e| {
+        let pos = dom::error_pos(&e);
+        XplError::Xml {
+            source: e,
+            file: file.to_string(),
+            line: pos.line,
+            col: pos.col,
+        }
+    })?;
+    let src = Source {
+        path: file,
+        diags: RefCell::new(diags),
+    };
+    let mut functions = Vec::new();
+    let mut stmts = Vec::new();
+    for node in &root.children {
+        match node {
+            XMLNode::Element(e) if e.name == "function" => functions.push(parse_function(e, &src)),
+            XMLNode::Element(e) => match parse_stmt(e, &src, 0) {
+                Ok(stmt) => stmts.push(stmt),
+                Err(err) => src.report(err),
+            },
+            XMLNode::Text(_) => {}
+        }
+    }
+    check_loop_control(&stmts, false, &src);
+    Ok((functions, stmts))
+}
+
+/// Parse a `<function>` element with its params, body and declared return type
+fn parse_function(elem: &Element, src: &Source) -> Function {
+    // optional function-level description
+    let func_desc = elem
+        .get_child("description")
+        .map(|d| d.text().trim().to_string());
+    let name = elem.attr("name").unwrap_or_default().to_string();
+    // collect parameters with optional type and description
+    let mut params = Vec::new();
+    for c in &elem.children {
+        if let XMLNode::Element(e) = c
+            && e.name == "param"
+        {
+            let name = e.attr("name").unwrap_or_default().to_string();
+            let ptype = e.attr("type").map(str::to_string);
+            // optional description child
+            let desc = e
+                .get_child("description")
+                .map(|d| d.text().trim().to_string());
+            params.push(Param {
+                name,
+                ptype,
+                description: desc,
+                span: e.span,
+            });
+        }
+    }
+    let body = match elem.get_child("body") {
+        Some(body_elem) => parse_block(body_elem, src, 0),
+        None => Vec::new(),
+    };
+    check_loop_control(&body, false, src);
+    let ret_type = elem
+        .get_child("return")
+        .and_then(|r| r.attr("type"))
+        .map(str::to_string);
+    Function {
+        name,
+        description: func_desc,
+        params,
+        ret_type,
+        body,
+        file: src.path.to_string(),
+        span: elem.span,
+    }
+}
+
+/// Build a semantic error pointing at the start of `span`
+fn error(src: &Source, span: Span, code: Code, msg: String) -> XplError {
+    XplError::Semantic {
+        code,
+        msg,
+        file: src.path.to_string(),
+        line: span.start.line,
+        col: span.start.col,
+    }
+}
+
+/// Parse every child element of a block (`<body>`, `<then>`, `<loop>`, ...) as a statement,
+/// reporting and skipping the ones that are invalid. `depth` counts the statements around
+/// the block; one nested too deeply is reported once and left empty.
+fn parse_block(block: &Element, src: &Source, depth: usize) -> Vec<Stmt> {
+    let mut stmts = Vec::new();
+    let mut children = block.children.iter().filter_map(|n| match n {
+        XMLNode::Element(e) => Some(e),
+        _ => None,
+    });
+    if depth == MAX_STMT_DEPTH {
+        if let Some(first) = children.next() {
+            src.report(error(
+                src,
+                first.span,
+                Code::TooDeep,
+                format!(
+                    "Statement is nested more than {} levels deep",
+                    MAX_STMT_DEPTH
+                ),
+            ));
+        }
+        return stmts;
+    }
+    for e in children {
+        match parse_stmt(e, src, depth) {
+            Ok(stmt) => stmts.push(stmt),
+            Err(err) => src.report(err),
+        }
+    }
+    stmts
+}
+
+/// Parse a required block child such as `<then>`; a missing one is reported and left empty
+fn parse_required_block(elem: &Element, name: &str, src: &Source, depth: usize) -> Vec<Stmt> {
+    match elem.get_child(name) {
+        Some(block) => parse_block(block, src, depth),
+        None => {
+            src.report(error(
+                src,
+                elem.span,
+                Code::MissingPart,
+                format!("Missing <{}> in <{}>", name, elem.name),
+            ));
+            Vec::new()
+        }
+    }
+}
+
+/// Parse a single statement element with `depth` statements around it, recursing into
+/// nested blocks
+fn parse_stmt(elem: &Element, src: &Source, depth: usize) -> Result<Stmt, XplError> {
+    let span = elem.span;
+    let kind = match elem.name.as_str() {
+        "var" => {
+            let name = elem.attr("name").ok_or_else(|| {
+                error(
+                    src,
+                    span,
+                    Code::MissingPart,
+                    "Missing name attribute on <var>".to_string(),
+                )
+            })?;
+            StmtKind::Var {
+                name: name.to_string(),
+                vtype: elem.attr("type").map(str::to_string),
+            }
+        }
+        "assign" => {
+            let var = elem.attr("var").ok_or_else(|| {
+                error(
+                    src,
+                    span,
+                    Code::MissingPart,
+                    "Missing var attribute on <assign>".to_string(),
+                )
+            })?;
+            StmtKind::Assign {
+                var: var.to_string(),
+                expr: parse_content(elem, src, 0)?,
+            }
+        }
+        "print" => {
+            let expr = interpolate(parse_content(elem, src, 0)?, src)?;
+            StmtKind::Print(check_depth(expr, 0, src)?)
+        }
+        "call" => {
+            let (name, args) = parse_call(elem, src, 0)?;
+            StmtKind::Call(name, args)
+        }
+        "return" => {
+            // a bare <return/> yields null, like falling off the end of a function
+            if has_content(elem) {
+                StmtKind::Return(parse_content(elem, src, 0)?)
+            } else {
+                StmtKind::Return(Expr::new(ExprKind::LiteralNull, span))
+            }
+        }
+        "if" => {
+            let cond_elem = elem.get_child("condition").ok_or_else(|| {
+                error(
+                    src,
+                    span,
+                    Code::MissingPart,
+                    "Missing condition".to_string(),
+                )
+            })?;
+            StmtKind::If {
+                cond: parse_content(cond_elem, src, 0)?,
+                then_body: parse_required_block(elem, "then", src, depth + 1),
+                else_body: parse_required_block(elem, "else", src, depth + 1),
+            }
+        }
+        "loop" => {
+            let count = match elem.attributes.get("times") {
+                Some(times) => parse_text_expr(times, src)?,
+                None => Expr::new(ExprKind::LiteralInt(0), span),
+            };
+            StmtKind::Loop {
+                var: elem.attr("var").map(str::to_string),
+                count,
+                body: parse_block(elem, src, depth + 1),
+            }
+        }
+        "for" => {
+            let attr = |name: &str| {
+                elem.attributes.get(name).ok_or_else(|| {
+                    error(
+                        src,
+                        span,
+                        Code::MissingPart,
+                        format!("Missing {} attribute on <for>", name),
+                    )
+                })
+            };
+            let step = match elem.attributes.get("step") {
+                Some(step) => parse_text_expr(step, src)?,
+                None => Expr::new(ExprKind::LiteralInt(1), span),
+            };
+            let inclusive = match elem.attributes.get("inclusive") {
+                None => false,
+                Some(t) if t.text == "false" => false,
+                Some(t) if t.text == "true" => true,
+                Some(t) => {
+                    return Err(error(
+                        src,
+                        t.span_at(0, t.text.len()),
+                        Code::InvalidLoop,
+                        format!("inclusive must be true or false, got {}", t.text),
+                    ));
+                }
+            };
+            StmtKind::For {
+                var: attr("var")?.text.clone(),
+                from: parse_text_expr(attr("from")?, src)?,
+                to: parse_text_expr(attr("to")?, src)?,
+                step,
+                inclusive,
+                body: parse_block(elem, src, depth + 1),
+            }
+        }
+        "while" => {
+            let cond_elem = elem.get_child("condition").ok_or_else(|| {
+                error(
+                    src,
+                    span,
+                    Code::MissingPart,
+                    "Missing condition".to_string(),
+                )
+            })?;
+            StmtKind::While {
+                cond: parse_content(cond_elem, src, 0)?,
+                body: parse_required_block(elem, "body", src, depth + 1),
+            }
+        }
+        "break" => StmtKind::Break,
+        "continue" => StmtKind::Continue,
+        other => {
+            return Err(error(
+                src,
+                span,
+                Code::UnknownElement,
+                format!("Unknown element <{}>", other),
+            ));
+        }
+    };
+    Ok(Stmt::new(kind, span))
+}
+
+/// Report `<break/>` and `<continue/>` that are not inside a loop
+fn check_loop_control(stmts: &[Stmt], in_loop: bool, src: &Source) {
+    for stmt in stmts {
+        match &stmt.kind {
+            StmtKind::Break if !in_loop => {
+                src.report(error(
+                    src,
+                    stmt.span,
+                    Code::LoopControlOutsideLoop,
+                    "<break/> outside of a loop".to_string(),
+                ));
+            }
+            StmtKind::Continue if !in_loop => {
+                src.report(error(
+                    src,
+                    stmt.span,
+                    Code::LoopControlOutsideLoop,
+                    "<continue/> outside of a loop".to_string(),
+                ));
+            }
+            StmtKind::If {
+                then_body,
+                else_body,
+                ..
+            } => {
+                check_loop_control(then_body, in_loop, src);
+                check_loop_control(else_body, in_loop, src);
+            }
+            StmtKind::Loop { body, .. }
+            | StmtKind::For { body, .. }
+            | StmtKind::While { body, .. } => {
+                check_loop_control(body, true, src);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Whether an element has a child element or non-blank text
+fn has_content(elem: &Element) -> bool {
+    elem.children.iter().any(|n| match n {
+        XMLNode::Element(_) => true,
+        XMLNode::Text(t) => !t.text.trim().is_empty(),
+    })
+}
+
+/// Parse the expression held by an element, combining its text and child elements.
+/// `depth` counts the operator elements around it, which count towards how deeply it nests.
+fn parse_content(elem: &Element, src: &Source, depth: usize) -> Result<Expr, XplError> {
+    let mut pieces = Vec::new();
+    for node in &elem.children {
+        match node {
+            XMLNode::Element(e) => pieces.push(Piece::Expr(parse_expr(e, src, depth)?)),
+            XMLNode::Text(t) => pieces.push(Piece::Text(t)),
+        }
+    }
+    if pieces.is_empty() {
+        return Err(error(
+            src,
+            elem.span,
+            Code::InvalidExpression,
+            "Expected expression".to_string(),
+        ));
+    }
+    parse_pieces(pieces, src, depth)
+}
+
+/// Parse a text expression such as an attribute value
+fn parse_text_expr(txt: &Text, src: &Source) -> Result<Expr, XplError> {
+    parse_pieces(vec![Piece::Text(txt)], src, 0)
+}
+
+/// Run the infix expression parser, turning its errors into located semantic errors
+fn parse_pieces(pieces: Vec<Piece>, src: &Source, depth: usize) -> Result<Expr, XplError> {
+    expr::parse(pieces, depth).map_err(|e| error(src, e.span, e.code, e.msg))
+}
+
+/// Fail if `expr`, built under `depth` operator elements, nests deeper than expressions may
+fn check_depth(expr: Expr, depth: usize, src: &Source) -> Result<Expr, XplError> {
+    if depth + expr::depth(&expr) > MAX_EXPR_DEPTH {
+        return Err(too_deep(expr.span, src));
+    }
+    Ok(expr)
+}
+
+fn too_deep(span: Span, src: &Source) -> XplError {
+    let e = expr::too_deep(span);
+    error(src, e.span, e.code, e.msg)
+}
+
+/// Expand `{expr}` placeholders in the string literals of a print expression
+fn interpolate(expr: Expr, src: &Source) -> Result<Expr, XplError> {
+    match expr.kind {
+        ExprKind::LiteralStr(s) => interpolate_str(&s, expr.span, src),
+        ExprKind::BinaryOp(op, l, r) => Ok(Expr::new(
+            ExprKind::BinaryOp(
+                op,
+                Box::new(interpolate(*l, src)?),
+                Box::new(interpolate(*r, src)?),
+            ),
+            expr.span,
+        )),
+        kind => Ok(Expr::new(kind, expr.span)),
+    }
+}
+
+/// Turn `"a {x} b"` into `"a " + x + " b"`; `{{` and `}}` stand for literal braces
+fn interpolate_str(s: &str, span: Span, src: &Source) -> Result<Expr, XplError> {
+    let literal_expr = |text: String| Expr::new(ExprKind::LiteralStr(text), span);
+    let mut parts = Vec::new();
+    let mut literal = String::new();
+    let mut chars = s.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '{' if chars.peek().map(|&(_, n)| n) == Some('{') => {
+                chars.next();
+                literal.push('{');
+            }
+            '}' if chars.peek().map(|&(_, n)| n) == Some('}') => {
+                chars.next();
+                literal.push('}');
+            }
+            '{' => {
+                let close = s[i..].find('}').map(|j| i + j).ok_or_else(|| {
+                    error(
+                        src,
+                        span,
+                        Code::InvalidExpression,
+                        format!("Unclosed '{{' in string \"{}\"", s),
+                    )
+                })?;
+                if parts.is_empty() || !literal.is_empty() {
+                    parts.push(literal_expr(std::mem::take(&mut literal)));
                 }
-            })
-            .map(|p| {
-                if let Some(child) = p.children.iter().find_map(|n| {
-                    if let XMLNode::Element(e) = n {
-                        Some(e)
-                    } else {
-                        None
-                    }
-                }) {
-                    parse_expr(child)
-                } else {
-                    let txt = p.get_text().unwrap_or_default();
-                    Ok(parse_text_expr(&txt))
+                // placeholders sit inside the quotes; escapes before them may shift columns
+                let start = Pos::new(
+                    span.start.line,
+                    span.start.col + 1 + s[..=i].chars().count(),
+                );
+                let inner = Text::new(s[i + 1..close].to_string(), start);
+                parts.push(parse_text_expr(&inner, src)?);
+                while chars.peek().is_some_and(|&(j, _)| j <= close) {
+                    chars.next();
                 }
-            })
-            .collect::<Result<Vec<_>, _>>()?;
-        return Ok(Expr::Call(func, args));
+            }
+            _ => literal.push(c),
+        }
+    }
+    if parts.is_empty() {
+        return Ok(literal_expr(literal));
     }
-    // Otherwise literal or varref
-    let txt = elem.get_text().unwrap_or_default().trim().to_string();
-    if txt.starts_with('"') && txt.ends_with('"') {
-        Ok(Expr::LiteralStr(txt.trim_matches('"').to_string()))
-    } else if let Ok(i) = txt.parse::<i64>() {
-        Ok(Expr::LiteralInt(i))
-    } else {
-        Ok(Expr::VarRef(txt))
+    if !literal.is_empty() {
+        parts.push(literal_expr(literal));
     }
+    // the leading literal (possibly empty) makes every `+` a string concatenation
+    Ok(parts
+        .into_iter()
+        .reduce(|acc, e| {
+            Expr::new(
+                ExprKind::BinaryOp(BinOp::Add, Box::new(acc), Box::new(e)),
+                span,
+            )
+        })
+        .unwrap())
+}
+
+/// Parse an operator element with `depth` operator elements around it
+fn parse_expr(elem: &Element, src: &Source, depth: usize) -> Result<Expr, XplError> {
+    let span = elem.span;
+    if depth == MAX_EXPR_DEPTH {
+        return Err(too_deep(span, src));
+    }
+    let binary = |op: BinOp, l: Expr, r: Expr| {
+        Expr::new(ExprKind::BinaryOp(op, Box::new(l), Box::new(r)), span)
+    };
+    let op = match elem.name.as_str() {
+        "call" => {
+            let (func, args) = parse_call(elem, src, depth + 1)?;
+            return Ok(Expr::new(ExprKind::Call(func, args), span));
+        }
+        "concat" => {
+            // start from "" so non-string operands are joined by their display form
+            let joined = parse_operands(elem, src, depth + 1)?.into_iter().fold(
+                Expr::new(ExprKind::LiteralStr(String::new()), span),
+                |acc, e| binary(BinOp::Add, acc, e),
+            );
+            // every operand adds a level to the chain it is folded into
+            return check_depth(joined, depth, src);
+        }
+        "not" => {
+            let operand = expect_operands(elem, src, 1, depth + 1)?.remove(0);
+            return Ok(Expr::new(
+                ExprKind::UnaryOp(UnOp::Not, Box::new(operand)),
+                span,
+            ));
+        }
+        "and" | "or" => {
+            let op = if elem.name == "and" {
+                BinOp::And
+            } else {
+                BinOp::Or
+            };
+            let operands = parse_operands(elem, src, depth + 1)?;
+            if operands.len() < 2 {
+                return Err(error(
+                    src,
+                    span,
+                    Code::InvalidExpression,
+                    format!(
+                        "<{}> expects at least 2 operands, got {}",
+                        elem.name,
+                        operands.len()
+                    ),
+                ));
+            }
+            // fold left so evaluation order (and short-circuiting) follows the document
+            let chain = operands
+                .into_iter()
+                .reduce(|l, r| binary(op.clone(), l, r))
+                .unwrap();
+            return check_depth(chain, depth, src);
+        }
+        "eq" => BinOp::Equal,
+        "ne" => BinOp::NotEqual,
+        "lt" => BinOp::Less,
+        "le" => BinOp::LessEqual,
+        "gt" => BinOp::Greater,
+        "ge" => BinOp::GreaterEqual,
+        other => {
+            return Err(error(
+                src,
+                span,
+                Code::UnknownElement,
+                format!("Unknown expression element <{}>", other),
+            ));
+        }
+    };
+    let mut operands = expect_operands(elem, src, 2, depth + 1)?;
+    let right = operands.pop().unwrap();
+    let left = operands.pop().unwrap();
+    Ok(binary(op, left, right))
+}
+
+/// Operands of an operator element: each `<param>` or nested expression element, or else its text
+fn parse_operands(elem: &Element, src: &Source, depth: usize) -> Result<Vec<Expr>, XplError> {
+    let children: Vec<&Element> = elem
+        .children
+        .iter()
+        .filter_map(|n| match n {
+            XMLNode::Element(e) => Some(e),
+            _ => None,
+        })
+        .collect();
+    if children.is_empty() {
+        return Ok(vec![parse_content(elem, src, depth)?]);
+    }
+    children
+        .into_iter()
+        .map(|c| {
+            if c.name == "param" {
+                parse_content(c, src, depth)
+            } else {
+                parse_expr(c, src, depth)
+            }
+        })
+        .collect()
+}
+
+/// Parse operands, requiring exactly `n` of them
+fn expect_operands(
+    elem: &Element,
+    src: &Source,
+    n: usize,
+    depth: usize,
+) -> Result<Vec<Expr>, XplError> {
+    let operands = parse_operands(elem, src, depth)?;
+    if operands.len() != n {
+        return Err(error(
+            src,
+            elem.span,
+            Code::InvalidExpression,
+            format!(
+                "<{}> expects {} operand{}, got {}",
+                elem.name,
+                n,
+                if n == 1 { "" } else { "s" },
+                operands.len()
+            ),
+        ));
+    }
+    Ok(operands)
+}
+
+/// Parse a `<call function="...">` element into its name and `<param>` arguments
+fn parse_call(elem: &Element, src: &Source, depth: usize) -> Result<(String, Vec<Expr>), XplError> {
+    let func = elem.attr("function").unwrap_or_default().to_string();
+    Ok((func, parse_params(elem, src, depth)?))
+}
+
+/// Parse the `<param>` children of an element as expressions
+fn parse_params(elem: &Element, src: &Source, depth: usize) -> Result<Vec<Expr>, XplError> {
+    elem.children
+        .iter()
+        .filter_map(|node| {
+            if let XMLNode::Element(e) = node {
+                if e.name == "param" { Some(e) } else { None }
+            } else {
+                None
+            }
+        })
+        .map(|p| parse_content(p, src, depth))
+        .collect()
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+
+    fn int(i: i64) -> Expr {
+        Expr::from(ExprKind::LiteralInt(i))
+    }
+
+    fn stmt(kind: StmtKind) -> Stmt {
+        Stmt::new(kind, Span::default())
+    }
+
     #[test]
     fn parse_simple_print() {
         let tmp = "<program name=\"t\" version=\"1.0\"><function name=\"main\"><body><print>10</print></body></function></program>";
@@ -434,6 +976,95 @@ mod tests {
         std::fs::write(&path, tmp).unwrap();
         let prog = parse_file(path.to_str().unwrap()).unwrap();
         let func = prog.functions.get("main").unwrap();
-        assert_eq!(func.body, vec![Stmt::Print(Expr::LiteralInt(10))]);
+        assert_eq!(func.body, vec![stmt(StmtKind::Print(int(10)))]);
+    }
+
+    #[test]
+    fn parse_nested_blocks() {
+        let tmp = "<program name=\"t\" version=\"1.0\"><function name=\"main\"><body>\
+            <loop times=\"2\"><if><condition>1</condition>\
+            <then><assign var=\"x\">5</assign><loop times=\"3\"><print>x</print></loop></then>\
+            <else><return>0</return></else></if></loop></body></function></program>";
+        let path = std::env::temp_dir().join("nested_blocks.xpl");
+        std::fs::write(&path, tmp).unwrap();
+        let prog = parse_file(path.to_str().unwrap()).unwrap();
+        let func = prog.functions.get("main").unwrap();
+        assert_eq!(
+            func.body,
+            vec![stmt(StmtKind::Loop {
+                var: None,
+                count: int(2),
+                body: vec![stmt(StmtKind::If {
+                    cond: int(1),
+                    then_body: vec![
+                        stmt(StmtKind::Assign {
+                            var: "x".to_string(),
+                            expr: int(5),
+                        }),
+                        stmt(StmtKind::Loop {
+                            var: None,
+                            count: int(3),
+                            body: vec![stmt(StmtKind::Print(Expr::from(ExprKind::VarRef(
+                                "x".to_string()
+                            ))))],
+                        }),
+                    ],
+                    else_body: vec![stmt(StmtKind::Return(int(0)))],
+                })],
+            })]
+        );
+    }
+
+    #[test]
+    fn node_spans() {
+        let tmp = "<program name=\"t\" version=\"1.0\">\n\
+                   <function name=\"main\">\n\
+                   <param name=\"n\" type=\"int\"/>\n\
+                   <body>\n  <loop times=\"n * 2\">\n    <print>n + 1</print>\n  </loop>\n</body>\n\
+                   </function></program>";
+        let path = std::env::temp_dir().join("node_spans.xpl");
+        std::fs::write(&path, tmp).unwrap();
+        let prog = parse_file(path.to_str().unwrap()).unwrap();
+        let func = prog.functions.get("main").unwrap();
+        assert_eq!(func.span, Span::new(Pos::new(2, 1), Pos::new(9, 12)));
+        assert_eq!(
+            func.params[0].span,
+            Span::new(Pos::new(3, 1), Pos::new(3, 29))
+        );
+        let stmt = &func.body[0];
+        assert_eq!(stmt.span, Span::new(Pos::new(5, 3), Pos::new(7, 10)));
+        let StmtKind::Loop { count, body, .. } = &stmt.kind else {
+            panic!("expected a loop");
+        };
+        assert_eq!(count.span, Span::new(Pos::new(5, 16), Pos::new(5, 21)));
+        let StmtKind::Print(expr) = &body[0].kind else {
+            panic!("expected a print");
+        };
+        assert_eq!(expr.span, Span::new(Pos::new(6, 12), Pos::new(6, 17)));
+    }
+
+    #[test]
+    fn unknown_element_error() {
+        let tmp = "<program name=\"t\" version=\"1.0\"><function name=\"main\"><body><then><print>1</print></then><prnt>1</prnt></body></function></program>";
+        let path = std::env::temp_dir().join("unknown_elem.xpl");
+        std::fs::write(&path, tmp).unwrap();
+        let err = parse_file(path.to_str().unwrap()).unwrap_err().to_string();
+        assert!(err.contains(":1:61: Unknown element <then>"), "{}", err);
+    }
+
+    #[test]
+    fn parse_snippet_splits_functions_and_statements() {
+        let mut diags = Diagnostics::new();
+        let text = "<function name=\"f\"><body><return>1</return></body></function>\n\
+                    <print>f()</print><prnt/><continue/>";
+        let (functions, stmts) = parse_snippet(text, "<repl>", &mut diags).unwrap();
+        assert_eq!(functions.len(), 1);
+        assert_eq!(functions[0].file, "<repl>");
+        assert_eq!(stmts.len(), 2);
+        let found: Vec<&str> = diags.iter().map(|d| d.msg.as_str()).collect();
+        assert_eq!(
+            found,
+            ["Unknown element <prnt>", "<continue/> outside of a loop"]
+        );
     }
 }
@@ -0,0 +1,85 @@
+// src/render.rs
+
+use crate::span::Span;
+use ansi_term::Style;
+use std::io::IsTerminal;
+
+/// Whether to color output on stderr: only for a terminal, and never when `NO_COLOR` is set
+pub(crate) fn use_color() -> bool {
+    let no_color = std::env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty());
+    !no_color && std::io::stderr().is_terminal()
+}
+
+/// Applies styles only when color is enabled
+#[derive(Clone, Copy)]
+pub(crate) struct Painter {
+    pub color: bool,
+}
+
+impl Painter {
+    pub fn paint(self, style: Style, text: &str) -> String {
+        if self.color {
+            style.paint(text).to_string()
+        } else {
+            text.to_string()
+        }
+    }
+}
+
+/// One span to underline in a snippet
+pub(crate) struct Mark<'a> {
+    pub span: Span,
+    /// `^` for the primary span, `-` for secondary ones
+    pub underline: char,
+    pub style: Style,
+    pub msg: &'a str,
+}
+
+/// Width of the line-number gutter needed for `line`
+pub(crate) fn gutter_width(line: usize) -> usize {
+    line.max(1).to_string().len()
+}
+
+/// Render the source line under `mark.span` with the span underlined and labeled.
+/// Nothing is rendered when the position is unknown or the file cannot be read.
+pub(crate) fn snippet(out: &mut String, file: &str, mark: &Mark, width: usize, p: Painter) {
+    let pos = mark.span.start;
+    let src = match std::fs::read_to_string(file) {
+        Ok(src) if pos.line > 0 => src,
+        _ => return,
+    };
+    let Some(line) = src.lines().nth(pos.line - 1) else {
+        return;
+    };
+    let gutter = Style::new().fg(ansi_term::Colour::Blue).bold();
+    let bar = p.paint(gutter, "|");
+    let number = p.paint(gutter, &format!("{:>width$}", pos.line));
+    out.push_str(&format!("{} {}\n", " ".repeat(width), bar));
+    out.push_str(&format!("{} {} {}\n", number, bar, line));
+    // keep tabs so the underline lines up with the code above it
+    let indent: String = line
+        .chars()
+        .take(pos.col.saturating_sub(1))
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+    let line_len = line.chars().count();
+    let end_col = if mark.span.end.line == pos.line {
+        mark.span.end.col
+    } else {
+        line_len + 1
+    };
+    let len = end_col.saturating_sub(pos.col).max(1);
+    let underline = mark.underline.to_string().repeat(len);
+    let mut label = p.paint(mark.style, &underline);
+    if !mark.msg.is_empty() {
+        label.push(' ');
+        label.push_str(&p.paint(mark.style, mark.msg));
+    }
+    out.push_str(&format!(
+        "{} {} {}{}\n",
+        " ".repeat(width),
+        bar,
+        indent,
+        label
+    ));
+}
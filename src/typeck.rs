@@ -0,0 +1,516 @@
+// src/typeck.rs
+
+use crate::callee::Callee;
+use crate::codes::Code;
+use crate::diagnostics::Diagnostics;
+use crate::native::Signature;
+use crate::parser::{BinOp, Expr, ExprKind, Function, Program, Stmt, StmtKind, UnOp};
+use crate::span::Span;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+/// Static type of an expression; `Any` when it cannot be known before running
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Type {
+    Int,
+    Float,
+    Decimal,
+    Str,
+    Bool,
+    List,
+    Null,
+    Any,
+}
+
+impl Type {
+    /// Parse a `type` attribute value
+    pub fn from_name(name: &str) -> Option<Type> {
+        Some(match name {
+            "int" => Type::Int,
+            "float" => Type::Float,
+            "decimal" => Type::Decimal,
+            "string" => Type::Str,
+            "bool" => Type::Bool,
+            "list" => Type::List,
+            "any" => Type::Any,
+            _ => return None,
+        })
+    }
+
+    /// Whether a value of type `actual` may be stored where `self` is declared
+    fn accepts(self, actual: Type) -> bool {
+        self == Type::Any
+            || actual == Type::Any
+            || self == actual
+            || (matches!(self, Type::Float | Type::Decimal) && actual == Type::Int)
+    }
+
+    fn is_numeric(self) -> bool {
+        matches!(self, Type::Int | Type::Float | Type::Decimal | Type::Any)
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Type::Int => "int",
+            Type::Float => "float",
+            Type::Decimal => "decimal",
+            Type::Str => "string",
+            Type::Bool => "bool",
+            Type::List => "list",
+            Type::Null => "null",
+            Type::Any => "any",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Check declared variable, parameter and return types of every function.
+/// Problems are reported in source order per function.
+pub fn check(prog: &Program, diags: &mut Diagnostics) {
+    check_with(prog, &[], diags);
+}
+
+/// Like `check`, also checking calls to the functions a host provides
+pub fn check_with(prog: &Program, natives: &[Signature], diags: &mut Diagnostics) {
+    let mut checker = Checker {
+        prog,
+        natives,
+        diags,
+    };
+    let mut names: Vec<&String> = prog.functions.keys().collect();
+    names.sort();
+    for name in names {
+        checker.check_function(&prog.functions[name]);
+    }
+}
+
+struct Checker<'a> {
+    prog: &'a Program,
+    natives: &'a [Signature],
+    diags: &'a mut Diagnostics,
+}
+
+/// Per-function state while walking its body in document order
+struct Scope<'a> {
+    func: &'a Function,
+    /// Variables whose `<var>` has been seen, with their declared type and where
+    declared: HashMap<String, (Type, Span)>,
+    /// Every name declared anywhere in the function, to catch uses that come first
+    declared_later: HashSet<String>,
+    ret_type: Option<Type>,
+}
+
+impl<'a> Checker<'a> {
+    fn error(&mut self, func: &Function, span: Span, code: Code, msg: String) {
+        self.diags.error(&func.file, span, code, msg);
+    }
+
+    /// Resolve the `type` attribute of the node at `span`, reporting unknown names
+    fn resolve(&mut self, func: &Function, name: &Option<String>, span: Span) -> Option<Type> {
+        let name = name.as_ref()?;
+        let ty = Type::from_name(name);
+        if ty.is_none() {
+            self.error(
+                func,
+                span,
+                Code::UnknownType,
+                format!("Unknown type '{}'", name),
+            );
+        }
+        ty
+    }
+
+    fn check_function(&mut self, func: &'a Function) {
+        let mut declared = HashMap::new();
+        for p in &func.params {
+            let ty = self.resolve(func, &p.ptype, p.span).unwrap_or(Type::Any);
+            declared.insert(p.name.clone(), (ty, p.span));
+        }
+        let mut declared_later = HashSet::new();
+        collect_declarations(&func.body, &mut declared_later);
+        let ret_type = self.resolve(func, &func.ret_type, func.span);
+        let mut scope = Scope {
+            func,
+            declared,
+            declared_later,
+            ret_type,
+        };
+        self.check_block(&func.body, &mut scope);
+    }
+
+    fn check_block(&mut self, stmts: &[Stmt], scope: &mut Scope<'a>) {
+        for stmt in stmts {
+            self.check_stmt(stmt, scope);
+        }
+    }
+
+    fn check_stmt(&mut self, stmt: &Stmt, scope: &mut Scope<'a>) {
+        let func = scope.func;
+        let span = stmt.span;
+        match &stmt.kind {
+            StmtKind::Var { name, vtype } => {
+                if scope.declared.contains_key(name) {
+                    self.error(
+                        func,
+                        span,
+                        Code::Redeclared,
+                        format!("Variable {} is already declared", name),
+                    );
+                }
+                let ty = self.resolve(func, vtype, span).unwrap_or(Type::Any);
+                scope.declared.insert(name.clone(), (ty, span));
+            }
+            StmtKind::Assign { var, expr } => {
+                let actual = self.infer(expr, scope);
+                self.check_declared_before_use(var, span, scope);
+                if let Some(&(declared, at)) = scope.declared.get(var)
+                    && !declared.accepts(actual)
+                {
+                    self.error(
+                        func,
+                        span,
+                        Code::TypeMismatch,
+                        format!(
+                            "Cannot assign {} to variable {} declared as {}",
+                            actual, var, declared
+                        ),
+                    );
+                    self.declared_here(func, var, at);
+                }
+            }
+            StmtKind::Print(expr) => {
+                self.infer(expr, scope);
+            }
+            StmtKind::Call(name, args) => {
+                self.infer_call(name, args, scope);
+            }
+            StmtKind::If {
+                cond,
+                then_body,
+                else_body,
+            } => {
+                self.infer(cond, scope);
+                self.check_block(then_body, scope);
+                self.check_block(else_body, scope);
+            }
+            StmtKind::Return(expr) => {
+                let actual = self.infer(expr, scope);
+                if let Some(expected) = scope.ret_type
+                    && !expected.accepts(actual)
+                {
+                    self.error(
+                        func,
+                        span,
+                        Code::TypeMismatch,
+                        format!(
+                            "Function {} returns {} but is declared to return {}",
+                            func.name, actual, expected
+                        ),
+                    );
+                }
+            }
+            StmtKind::Loop { var, count, body } => {
+                self.expect_int(count, "Loop count", scope);
+                if let Some(var) = var {
+                    self.bind_counter(var, span, scope);
+                }
+                self.check_block(body, scope);
+            }
+            StmtKind::For {
+                var,
+                from,
+                to,
+                step,
+                body,
+                ..
+            } => {
+                self.expect_int(from, "For range start", scope);
+                self.expect_int(to, "For range end", scope);
+                self.expect_int(step, "For step", scope);
+                self.bind_counter(var, span, scope);
+                self.check_block(body, scope);
+            }
+            StmtKind::While { cond, body } => {
+                self.infer(cond, scope);
+                self.check_block(body, scope);
+            }
+            StmtKind::Break | StmtKind::Continue => {}
+        }
+    }
+
+    /// A loop counter is an int variable; it must not clash with a differently typed declaration
+    fn bind_counter(&mut self, var: &str, span: Span, scope: &mut Scope<'a>) {
+        self.check_declared_before_use(var, span, scope);
+        if let Some(&(declared, at)) = scope.declared.get(var)
+            && !declared.accepts(Type::Int)
+        {
+            self.error(
+                scope.func,
+                span,
+                Code::TypeMismatch,
+                format!(
+                    "Loop variable {} is declared as {} but counts with int",
+                    var, declared
+                ),
+            );
+            self.declared_here(scope.func, var, at);
+        }
+    }
+
+    /// Point from a type mismatch back to the declaration it broke
+    fn declared_here(&mut self, func: &Function, var: &str, at: Span) {
+        self.diags.note(
+            &func.file,
+            at,
+            Code::TypeMismatch,
+            format!("Variable {} is declared here", var),
+        );
+    }
+
+    fn expect_int(&mut self, expr: &Expr, what: &str, scope: &mut Scope<'a>) {
+        let ty = self.infer(expr, scope);
+        if !Type::Int.accepts(ty) {
+            self.error(
+                scope.func,
+                expr.span,
+                Code::TypeMismatch,
+                format!("{} must be int, got {}", what, ty),
+            );
+        }
+    }
+
+    fn check_declared_before_use(&mut self, name: &str, span: Span, scope: &Scope<'a>) {
+        if !scope.declared.contains_key(name) && scope.declared_later.contains(name) {
+            self.error(
+                scope.func,
+                span,
+                Code::UsedBeforeDeclaration,
+                format!("Variable {} is used before its declaration", name),
+            );
+        }
+    }
+
+    /// Infer the static type of an expression, reporting type errors inside it
+    fn infer(&mut self, expr: &Expr, scope: &mut Scope<'a>) -> Type {
+        let func = scope.func;
+        match &expr.kind {
+            ExprKind::LiteralInt(_) | ExprKind::LiteralBigInt(_) => Type::Int,
+            ExprKind::LiteralFloat(_) => Type::Float,
+            ExprKind::LiteralDecimal(_) => Type::Decimal,
+            ExprKind::LiteralStr(_) => Type::Str,
+            ExprKind::LiteralBool(_) => Type::Bool,
+            ExprKind::LiteralNull => Type::Null,
+            ExprKind::VarRef(name) => {
+                self.check_declared_before_use(name, expr.span, scope);
+                scope.declared.get(name).map_or(Type::Any, |&(ty, _)| ty)
+            }
+            ExprKind::UnaryOp(UnOp::Not, e) => {
+                self.infer(e, scope);
+                Type::Bool
+            }
+            ExprKind::UnaryOp(UnOp::Negate, e) => {
+                let ty = self.infer(e, scope);
+                if !ty.is_numeric() {
+                    self.error(
+                        func,
+                        expr.span,
+                        Code::TypeMismatch,
+                        format!("Cannot negate a value of type {}", ty),
+                    );
+                    return Type::Any;
+                }
+                ty
+            }
+            ExprKind::BinaryOp(op, l, r) => {
+                let left = self.infer(l, scope);
+                let right = self.infer(r, scope);
+                self.binary_type(op, left, right, expr, scope)
+            }
+            ExprKind::Call(name, args) => self.infer_call(name, args, scope),
+        }
+    }
+
+    /// Check the arguments of a call against the callee's parameters and give its result type
+    fn infer_call(&mut self, name: &str, args: &[Expr], scope: &mut Scope<'a>) -> Type {
+        let arg_types: Vec<Type> = args.iter().map(|a| self.infer(a, scope)).collect();
+        let natives = self.natives;
+        let callee = match Callee::lookup(self.prog, name, |n| natives.iter().find(|s| s.name == n))
+        {
+            Some(Callee::Builtin(_)) => return builtin_type(name),
+            Some(callee) => callee,
+            // undefined functions are reported by analysis
+            None => return Type::Any,
+        };
+        for (((param, ptype), arg), actual) in callee.params().into_iter().zip(args).zip(arg_types)
+        {
+            let Some(expected) = ptype.and_then(Type::from_name) else {
+                continue;
+            };
+            if !expected.accepts(actual) {
+                self.error(
+                    scope.func,
+                    arg.span,
+                    Code::TypeMismatch,
+                    format!(
+                        "Argument {} of {} expects {}, got {}",
+                        param, name, expected, actual
+                    ),
+                );
+            }
+        }
+        callee
+            .ret_type()
+            .and_then(Type::from_name)
+            .unwrap_or(Type::Any)
+    }
+
+    fn binary_type(
+        &mut self,
+        op: &BinOp,
+        left: Type,
+        right: Type,
+        expr: &Expr,
+        scope: &Scope<'a>,
+    ) -> Type {
+        use Type::*;
+        let result = match op {
+            BinOp::And
+            | BinOp::Or
+            | BinOp::Equal
+            | BinOp::NotEqual
+            | BinOp::Less
+            | BinOp::LessEqual
+            | BinOp::Greater
+            | BinOp::GreaterEqual => {
+                let ordering =
+                    !matches!(op, BinOp::And | BinOp::Or | BinOp::Equal | BinOp::NotEqual);
+                let comparable = (left.is_numeric() && right.is_numeric())
+                    || matches!((left, right), (Str | Any, Str | Any));
+                if ordering && !comparable {
+                    None
+                } else {
+                    Some(Bool)
+                }
+            }
+            BinOp::Add if left == Str || right == Str => Some(Str),
+            _ => match (left, right) {
+                (Int, Int) => Some(Int),
+                (Float, Int | Float) | (Int, Float) => Some(Float),
+                // mixing in floats would bring back the rounding decimals avoid
+                (Decimal, Int | Decimal) | (Int, Decimal) => Some(Decimal),
+                (Any, l) | (l, Any) if l.is_numeric() || (l == Str && *op == BinOp::Add) => {
+                    Some(Any)
+                }
+                _ => None,
+            },
+        };
+        result.unwrap_or_else(|| {
+            self.error(
+                scope.func,
+                expr.span,
+                Code::TypeMismatch,
+                format!("Cannot apply '{}' to {} and {}", op, left, right),
+            );
+            Any
+        })
+    }
+}
+
+/// Result type of a builtin
+fn builtin_type(name: &str) -> Type {
+    match name {
+        "len" | "int" => Type::Int,
+        "float" => Type::Float,
+        "decimal" => Type::Decimal,
+        "split" | "args" => Type::List,
+        "contains" => Type::Bool,
+        "get" | "assert" => Type::Any,
+        _ => Type::Str,
+    }
+}
+
+/// Names declared with `<var>` anywhere in a block, including nested ones
+fn collect_declarations(stmts: &[Stmt], out: &mut HashSet<String>) {
+    for stmt in stmts {
+        match &stmt.kind {
+            StmtKind::Var { name, .. } => {
+                out.insert(name.clone());
+            }
+            StmtKind::If {
+                then_body,
+                else_body,
+                ..
+            } => {
+                collect_declarations(then_body, out);
+                collect_declarations(else_body, out);
+            }
+            StmtKind::Loop { body, .. }
+            | StmtKind::For { body, .. }
+            | StmtKind::While { body, .. } => {
+                collect_declarations(body, out);
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_file;
+
+    fn check_src(name: &str, src: &str) -> Vec<String> {
+        let path = std::env::temp_dir().join(name);
+        std::fs::write(&path, src).unwrap();
+        let prog = parse_file(path.to_str().unwrap()).unwrap();
+        let mut diags = Diagnostics::new();
+        check(&prog, &mut diags);
+        diags.iter().map(|d| d.to_string()).collect()
+    }
+
+    #[test]
+    fn examples_type_check() {
+        let prog = parse_file("examples/hello.xpl").unwrap();
+        let mut diags = Diagnostics::new();
+        check(&prog, &mut diags);
+        assert!(diags.is_empty());
+    }
+
+    #[test]
+    fn reports_every_problem() {
+        let errors = check_src(
+            "typeck_errors.xpl",
+            "<program name=\"t\" version=\"1.0\">\n\
+             <function name=\"half\"><param name=\"n\" type=\"int\"/><return type=\"int\"/>\n\
+             <body><return> n / 2.0 </return></body></function>\n\
+             <function name=\"main\"><body>\n\
+             <assign var=\"early\">1</assign>\n\
+             <var name=\"early\" type=\"int\"/>\n\
+             <var name=\"label\" type=\"string\"/>\n\
+             <assign var=\"label\">3</assign>\n\
+             <var name=\"w\" type=\"widget\"/>\n\
+             <print><call function=\"half\"><param>\"six\"</param></call></print>\n\
+             </body></function></program>",
+        );
+        let expected = [
+            (
+                ":3:7:",
+                "Function half returns float but is declared to return int",
+            ),
+            (":5:1:", "Variable early is used before its declaration"),
+            (
+                ":8:1:",
+                "Cannot assign int to variable label declared as string",
+            ),
+            (":7:1: note[XPL", "Variable label is declared here"),
+            (":9:1:", "Unknown type 'widget'"),
+            (":10:37:", "Argument n of half expects int, got string"),
+        ];
+        assert_eq!(errors.len(), expected.len(), "{:#?}", errors);
+        for (err, (pos, msg)) in errors.iter().zip(expected) {
+            assert!(err.contains(pos) && err.contains(msg), "{}", err);
+        }
+    }
+}
@@ -0,0 +1,99 @@
+// src/value.rs
+
+use num_bigint::BigInt;
+use rust_decimal::Decimal;
+use rust_decimal::prelude::ToPrimitive;
+use std::fmt;
+
+/// A runtime value flowing through variables, arguments and returns
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Int(i64),
+    /// An int beyond 64 bits, from overflowing arithmetic in a `bigint` program
+    BigInt(BigInt),
+    Float(f64),
+    /// An exact base-10 number, for amounts that must not pick up binary rounding
+    Decimal(Decimal),
+    Str(String),
+    Bool(bool),
+    List(Vec<Value>),
+    Null,
+}
+
+impl Value {
+    /// Name of the value's type as written in `type` attributes
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Int(_) | Value::BigInt(_) => "int",
+            Value::Float(_) => "float",
+            Value::Decimal(_) => "decimal",
+            Value::Str(_) => "string",
+            Value::Bool(_) => "bool",
+            Value::List(_) => "list",
+            Value::Null => "null",
+        }
+    }
+
+    /// An int, kept in 64 bits when it fits
+    pub fn from_bigint(n: BigInt) -> Value {
+        match i64::try_from(&n) {
+            Ok(i) => Value::Int(i),
+            Err(_) => Value::BigInt(n),
+        }
+    }
+
+    /// This value stored where type `ty` is declared, widening ints to floats and decimals
+    pub fn widen(self, ty: &str) -> Value {
+        match (ty, self) {
+            ("float", Value::Int(i)) => Value::Float(i as f64),
+            ("float", Value::BigInt(i)) => Value::Float(i.to_f64().unwrap_or(f64::NAN)),
+            ("decimal", Value::Int(i)) => Value::Decimal(i.into()),
+            // beyond the decimal range the int stays as it is and fails the type check
+            ("decimal", Value::BigInt(i)) => match Decimal::from_str_exact(&i.to_string()) {
+                Ok(d) => Value::Decimal(d),
+                Err(_) => Value::BigInt(i),
+            },
+            (_, v) => v,
+        }
+    }
+
+    /// Truthiness used by conditions and logical operators
+    pub fn is_truthy(&self) -> bool {
+        match self {
+            Value::Int(i) => *i != 0,
+            Value::BigInt(i) => *i != BigInt::ZERO,
+            Value::Float(f) => *f != 0.0,
+            Value::Decimal(d) => !d.is_zero(),
+            Value::Str(s) => !s.is_empty(),
+            Value::Bool(b) => *b,
+            Value::List(items) => !items.is_empty(),
+            Value::Null => false,
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Int(i) => write!(f, "{}", i),
+            Value::BigInt(i) => write!(f, "{}", i),
+            // keep a trailing `.0` so floats stay recognizable when printed
+            Value::Float(x) => write!(f, "{:?}", x),
+            // trailing zeros are kept, so `1.50d` prints as 1.50
+            Value::Decimal(d) => write!(f, "{}", d),
+            Value::Str(s) => write!(f, "{}", s),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::List(items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, "]")
+            }
+            Value::Null => write!(f, "null"),
+        }
+    }
+}
@@ -1,277 +1,1071 @@
 // src/vm.rs
 
+use crate::callee::Callee;
+use crate::codes::Code;
+use crate::diagnostics::Diagnostics;
 use crate::error::XplError;
-use crate::parser::{BinOp, Expr, Program, Stmt};
+use crate::limits::{Limit, Limits, MAX_NESTING};
+use crate::native::{NativeFn, Signature};
+use crate::parser::{Arithmetic, BinOp, Expr, ExprKind, Function, Program, Stmt, StmtKind, UnOp};
+use crate::span::Span;
+use crate::value::Value;
+use crate::{analysis, builtins, parser, typeck};
+use num_bigint::BigInt;
+use rust_decimal::Decimal;
+use rust_decimal::prelude::ToPrimitive;
+use std::cell::RefCell;
+use std::cmp::Ordering;
 use std::collections::HashMap;
+use std::io::Write;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::time::Instant;
+
+/// Local variables of one active function call
+struct Frame {
+    locals: HashMap<String, Value>,
+    /// Declared types of params and of variables whose `<var>` has run
+    types: HashMap<String, String>,
+    /// File the running function was defined in, for error locations
+    file: String,
+}
+
+impl Frame {
+    /// Frame for a call of `func` with its params bound in `locals`
+    fn new(func: &Function, locals: HashMap<String, Value>) -> Self {
+        let types = func
+            .params
+            .iter()
+            .filter_map(|p| Some((p.name.clone(), p.ptype.clone()?)))
+            .collect();
+        Frame {
+            locals,
+            types,
+            file: func.file.clone(),
+        }
+    }
+}
+
+/// How control leaves a statement or block
+enum Flow {
+    /// Fall through to the next statement
+    Next,
+    /// A `<return>` was executed; unwind to the enclosing call
+    Return(Value),
+    /// Leave the innermost loop
+    Break,
+    /// Skip to the next iteration of the innermost loop
+    Continue,
+}
+
+/// A writer that keeps what is written so it can be read back as lines. A VM prints
+/// into one unless given another writer, and returns what it collected.
+#[derive(Clone, Default)]
+pub struct Capture(Rc<RefCell<Vec<u8>>>);
+
+impl Capture {
+    /// Take the lines written since the last call
+    pub fn take_lines(&self) -> Vec<String> {
+        let bytes = std::mem::take(&mut *self.0.borrow_mut());
+        String::from_utf8_lossy(&bytes)
+            .lines()
+            .map(str::to_string)
+            .collect()
+    }
+}
+
+impl Write for Capture {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
 
 pub struct VM {
-    vars: HashMap<String, i64>,
+    frames: Vec<Frame>,
+    /// Where `<print>` writes, a line at a time as the script runs
+    out: Box<dyn Write>,
+    /// Collects the output while `out` is left at its default
+    capture: Capture,
     file: String,
+    /// Command-line arguments, bound to main's params and returned by `args()`
+    args: Vec<String>,
+    /// Exit status from main's return value, once `run` has finished
+    status: i32,
+    /// Functions provided by the host application
+    natives: HashMap<String, (Signature, NativeFn)>,
+    /// Variables shared by all functions and kept between runs and calls
+    globals: HashMap<String, Value>,
+    /// Where includes are looked up by `load_file` and `load_str`
+    include_paths: Vec<PathBuf>,
+    limits: Limits,
+    /// Steps taken and bytes printed since the current run or call started
+    steps: u64,
+    printed: usize,
+    /// Statements and expressions being run, through all active calls
+    depth: usize,
+    /// When the current run or call has to end by
+    deadline: Option<Instant>,
+}
+
+/// Settings for a VM, for hosts embedding xpl
+pub struct Builder {
+    vm: VM,
+}
+
+impl Builder {
+    /// Name used for the script in error locations when it is not read from a file
+    pub fn file(mut self, name: &str) -> Self {
+        self.vm.file = name.to_string();
+        self
+    }
+
+    /// Where the script prints to; see `VM::set_output`
+    pub fn output(mut self, out: impl Write + 'static) -> Self {
+        self.vm.set_output(out);
+        self
+    }
+
+    /// Arguments for main's params and `args()`
+    pub fn args(mut self, args: Vec<String>) -> Self {
+        self.vm.set_args(args);
+        self
+    }
+
+    /// Also look for includes in `dir`, after the script's own directory
+    pub fn include_path(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.vm.include_paths.push(dir.into());
+        self
+    }
+
+    /// A function the scripts can call; see `VM::register_native`
+    pub fn native(
+        mut self,
+        sig: Signature,
+        f: impl Fn(&[Value]) -> Result<Value, XplError> + 'static,
+    ) -> Self {
+        self.vm.register_native(sig, f);
+        self
+    }
+
+    /// A global variable and its initial value
+    pub fn global(mut self, name: &str, value: Value) -> Self {
+        self.vm.set_global(name, value);
+        self
+    }
+
+    /// Bounds on each run or call; see `VM::set_limits`
+    pub fn limits(mut self, limits: Limits) -> Self {
+        self.vm.set_limits(limits);
+        self
+    }
+
+    pub fn build(self) -> VM {
+        self.vm
+    }
 }
 
 impl VM {
     /// Create a VM with file context for error reporting
     pub fn new(file: String) -> Self {
+        let capture = Capture::default();
         VM {
-            vars: HashMap::new(),
+            frames: Vec::new(),
+            out: Box::new(capture.clone()),
+            capture,
             file,
+            args: Vec::new(),
+            status: 0,
+            natives: HashMap::new(),
+            globals: HashMap::new(),
+            include_paths: Vec::new(),
+            limits: Limits::default(),
+            steps: 0,
+            printed: 0,
+            depth: 0,
+            deadline: None,
         }
     }
 
-    /// Find (line, col) of the first occurrence of token in the source file
-    fn find_pos(&self, token: &str) -> (usize, usize) {
-        if let Ok(content) = std::fs::read_to_string(&self.file) {
-            for (i, line) in content.lines().enumerate() {
-                if let Some(idx) = line.find(token) {
-                    return (i, idx);
-                }
+    /// Bound what each run, call or batch of statements may do. A script that goes past
+    /// a limit stops with `XplError::LimitExceeded`.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    /// Configure a VM with chained calls, starting from what `new` would give
+    pub fn builder() -> Builder {
+        Builder {
+            vm: VM::new("<script>".to_string()),
+        }
+    }
+
+    /// Read, parse and check a script against this VM's natives, globals and include paths.
+    /// Fails with the script's diagnostics if it has errors.
+    pub fn load_file(&self, path: &str) -> Result<Program, Diagnostics> {
+        match std::fs::read_to_string(path) {
+            Ok(text) => self.load_str(&text, path),
+            Err(e) => {
+                let mut diags = Diagnostics::new();
+                diags.push(
+                    XplError::Io {
+                        source: e,
+                        file: path.to_string(),
+                        included_from: None,
+                    }
+                    .into(),
+                );
+                Err(diags)
             }
         }
-        (0, 0)
     }
 
-    /// Execute a Program and return printed outputs
+    /// Like `load_file` for source held in memory; `name` stands in for its file name
+    pub fn load_str(&self, text: &str, name: &str) -> Result<Program, Diagnostics> {
+        let mut diags = Diagnostics::new();
+        let prog = match parser::parse_str_with(text, name, &self.include_paths, &mut diags) {
+            Ok(prog) => prog,
+            Err(err) => {
+                diags.push(err.into());
+                return Err(diags);
+            }
+        };
+        let natives = self.native_signatures();
+        let globals: Vec<String> = self.globals.keys().cloned().collect();
+        analysis::check_with(&prog, &natives, &globals, &mut diags);
+        typeck::check_with(&prog, &natives, &mut diags);
+        if diags.has_errors() {
+            return Err(diags);
+        }
+        Ok(prog)
+    }
+
+    /// Call a function of the program, a native or a builtin with `args` and return its
+    /// result. What it prints goes to the writer set with `set_output`, if any.
+    pub fn call(&mut self, prog: &Program, name: &str, args: &[Value]) -> Result<Value, XplError> {
+        self.start_budget();
+        let res = self.call_function(prog, name, args.to_vec(), Span::default());
+        // nobody asked for output captured by default, so it does not pile up across calls
+        self.capture.take_lines();
+        res
+    }
+
+    /// Value of a global variable, as set by the host or last assigned by the script
+    pub fn global(&self, name: &str) -> Option<&Value> {
+        self.globals.get(name)
+    }
+
+    /// Create or update a global variable. Functions read globals they have no local or
+    /// param of the same name for, and assigning such a name updates the global.
+    pub fn set_global(&mut self, name: &str, value: Value) {
+        self.globals.insert(name.to_string(), value);
+    }
+
+    /// Let scripts call `f` under the signature's name. Arguments are checked against the
+    /// signature before `f` sees them. Script functions of the same name shadow it, and it
+    /// shadows builtins.
+    pub fn register_native(
+        &mut self,
+        sig: Signature,
+        f: impl Fn(&[Value]) -> Result<Value, XplError> + 'static,
+    ) {
+        self.natives.insert(sig.name.clone(), (sig, Box::new(f)));
+    }
+
+    /// Signatures of the registered natives by name, to check scripts against
+    pub fn native_signatures(&self) -> Vec<Signature> {
+        let mut sigs: Vec<Signature> = self.natives.values().map(|(s, _)| s.clone()).collect();
+        sigs.sort_by(|a, b| a.name.cmp(&b.name));
+        sigs
+    }
+
+    /// Print to `out` as the script runs instead of collecting the output, so the
+    /// functions that return what was printed return nothing
+    pub fn set_output(&mut self, out: impl Write + 'static) {
+        self.out = Box::new(out);
+    }
+
+    /// Set the command-line arguments the script is run with
+    pub fn set_args(&mut self, args: Vec<String>) {
+        self.args = args;
+    }
+
+    /// The int main returned, or 0 if it returned anything else
+    pub fn exit_status(&self) -> i32 {
+        self.status
+    }
+
+    /// Reset what the limits count, as a new run or call starts
+    fn start_budget(&mut self) {
+        self.steps = 0;
+        self.printed = 0;
+        self.depth = 0;
+        self.deadline = self.limits.time.map(|t| Instant::now() + t);
+    }
+
+    /// Build a limit error at `span` in the file of the running function
+    fn exceeded(&self, limit: Limit, span: Span) -> XplError {
+        let file = self.frames.last().map_or(&self.file, |f| &f.file);
+        XplError::LimitExceeded {
+            limit,
+            file: file.clone(),
+            line: span.start.line,
+            col: span.start.col,
+        }
+    }
+
+    /// Count one statement or expression against the step and time limits
+    fn step(&mut self, span: Span) -> Result<(), XplError> {
+        self.steps += 1;
+        if let Some(max) = self.limits.steps
+            && self.steps > max
+        {
+            return Err(self.exceeded(Limit::Steps(max), span));
+        }
+        if let Some(deadline) = self.deadline
+            && Instant::now() > deadline
+        {
+            let time = self.limits.time.unwrap_or_default();
+            return Err(self.exceeded(Limit::Time(time), span));
+        }
+        Ok(())
+    }
+
+    /// Check the `n`th iteration of the loop at `span` against the iteration limit
+    fn iteration(&self, n: u64, span: Span) -> Result<(), XplError> {
+        match self.limits.loop_iterations {
+            Some(max) if n > max => Err(self.exceeded(Limit::LoopIterations(max), span)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Build a semantic error at `span` in the file of the running function
+    fn error_at(&self, code: Code, msg: String, span: Span) -> XplError {
+        let file = self.frames.last().map_or(&self.file, |f| &f.file);
+        XplError::Semantic {
+            code,
+            msg,
+            file: file.clone(),
+            line: span.start.line,
+            col: span.start.col,
+        }
+    }
+
+    /// Execute a Program and return printed outputs, unless they went to another writer
     pub fn run(&mut self, prog: &Program) -> Result<Vec<String>, XplError> {
-        let mut outputs = Vec::new();
         // Find main function
         let main_fn = prog
             .functions
             .get("main")
             .ok_or_else(|| XplError::Semantic {
+                code: Code::MissingMain,
                 msg: "No main function".to_string(),
                 file: self.file.clone(),
                 line: 0,
                 col: 0,
             })?;
-        for stmt in &main_fn.body {
-            match stmt {
-                Stmt::Assign { var, expr } => {
-                    let val = self.eval_expr(expr, prog)?;
-                    self.vars.insert(var.clone(), val);
+        let locals = self.main_args(main_fn)?;
+        let (outputs, returned) = self.invoke(prog, main_fn, locals)?;
+        // exit statuses are truncated like those of any other process
+        self.status = match returned {
+            Value::Int(n) => n as i32,
+            _ => 0,
+        };
+        Ok(outputs)
+    }
+
+    /// Bind the command-line arguments to main's params, converted to their declared types.
+    /// A main without params can still read them through `args()`.
+    fn main_args(&self, main_fn: &Function) -> Result<HashMap<String, Value>, XplError> {
+        let mut locals = HashMap::new();
+        if main_fn.params.is_empty() {
+            return Ok(locals);
+        }
+        if main_fn.params.len() != self.args.len() {
+            return Err(self.error_at(
+                Code::ArgumentCount,
+                format!(
+                    "Expected {} args for function 'main', got {}",
+                    main_fn.params.len(),
+                    self.args.len()
+                ),
+                main_fn.span,
+            ));
+        }
+        for (i, (p, arg)) in main_fn.params.iter().zip(&self.args).enumerate() {
+            let value = match p.ptype.as_deref() {
+                Some("int") => arg.parse().ok().map(Value::Int),
+                Some("float") => arg.parse().ok().map(Value::Float),
+                Some("decimal") => Decimal::from_str_exact(arg).ok().map(Value::Decimal),
+                Some("bool") => arg.parse().ok().map(Value::Bool),
+                Some("list") => Some(Value::List(
+                    arg.split(',').map(|s| Value::Str(s.to_string())).collect(),
+                )),
+                _ => Some(Value::Str(arg.clone())),
+            };
+            let value = value.ok_or_else(|| {
+                self.error_at(
+                    Code::TypeMismatch,
+                    format!(
+                        "Argument {} of main expects {}, got \"{}\"",
+                        i + 1,
+                        p.ptype.as_deref().unwrap_or_default(),
+                        arg
+                    ),
+                    p.span,
+                )
+            })?;
+            locals.insert(p.name.clone(), value);
+        }
+        Ok(locals)
+    }
+
+    /// Run a function that takes no arguments, such as a test, returning what it printed
+    pub fn run_function(
+        &mut self,
+        prog: &Program,
+        func: &Function,
+    ) -> Result<Vec<String>, XplError> {
+        let (outputs, _) = self.invoke(prog, func, HashMap::new())?;
+        Ok(outputs)
+    }
+
+    /// Run a function from the top level, returning what it printed and what it returned
+    fn invoke(
+        &mut self,
+        prog: &Program,
+        func: &Function,
+        locals: HashMap<String, Value>,
+    ) -> Result<(Vec<String>, Value), XplError> {
+        self.start_budget();
+        self.frames.push(Frame::new(func, locals));
+        let res = self.exec_block(&func.body, prog);
+        self.frames.pop();
+        let returned = match res? {
+            Flow::Return(v) => v,
+            _ => Value::Null,
+        };
+        Ok((self.capture.take_lines(), returned))
+    }
+
+    /// Run statements outside of any function, keeping their variables for the next call
+    /// as the REPL does, and return what they printed
+    pub fn run_statements(
+        &mut self,
+        prog: &Program,
+        stmts: &[Stmt],
+    ) -> Result<Vec<String>, XplError> {
+        if self.frames.is_empty() {
+            self.frames.push(Frame {
+                locals: HashMap::new(),
+                types: HashMap::new(),
+                file: self.file.clone(),
+            });
+        }
+        self.start_budget();
+        let res = self.exec_block(stmts, prog);
+        // output before a failure is dropped so it does not show up after the next input
+        let outputs = self.capture.take_lines();
+        res?;
+        Ok(outputs)
+    }
+
+    /// Variables of the innermost call
+    fn locals(&mut self) -> &mut HashMap<String, Value> {
+        &mut self
+            .frames
+            .last_mut()
+            .expect("VM always runs inside a frame")
+            .locals
+    }
+
+    /// Execute statements in order, stopping early when control leaves the block
+    fn exec_block(&mut self, stmts: &[Stmt], prog: &Program) -> Result<Flow, XplError> {
+        for stmt in stmts {
+            match self.exec_stmt(stmt, prog)? {
+                Flow::Next => {}
+                flow => return Ok(flow),
+            }
+        }
+        Ok(Flow::Next)
+    }
+
+    /// Run `f` one level deeper within statements and expressions, failing before the
+    /// host's stack runs out
+    fn nested<T>(
+        &mut self,
+        span: Span,
+        f: impl FnOnce(&mut Self) -> Result<T, XplError>,
+    ) -> Result<T, XplError> {
+        if self.depth >= MAX_NESTING {
+            return Err(self.exceeded(Limit::Nesting(MAX_NESTING), span));
+        }
+        self.depth += 1;
+        let res = f(self);
+        self.depth -= 1;
+        res
+    }
+
+    /// Execute a single statement in the current frame
+    fn exec_stmt(&mut self, stmt: &Stmt, prog: &Program) -> Result<Flow, XplError> {
+        self.step(stmt.span)?;
+        self.nested(stmt.span, |vm| vm.exec_kind(stmt, prog))
+    }
+
+    fn exec_kind(&mut self, stmt: &Stmt, prog: &Program) -> Result<Flow, XplError> {
+        match &stmt.kind {
+            StmtKind::Var { name, vtype } => {
+                if let Some(ty) = vtype {
+                    let frame = self
+                        .frames
+                        .last_mut()
+                        .expect("VM always runs inside a frame");
+                    frame.types.insert(name.clone(), ty.clone());
                 }
-                Stmt::Print(expr) => {
-                    let out = match expr {
-                        Expr::LiteralStr(s) => s.clone(),
-                        Expr::LiteralInt(i) => i.to_string(),
-                        _ => self.eval_expr(expr, prog)?.to_string(),
-                    };
-                    outputs.push(out);
+            }
+            StmtKind::Assign { var, expr } => {
+                let mut val = self.eval_expr(expr, prog)?;
+                let frame = self.frames.last().expect("VM always runs inside a frame");
+                // the type checker lets ints stand in for floats and decimals; store what it assumed
+                if let Some(ty) = frame.types.get(var) {
+                    val = val.widen(ty);
                 }
-                Stmt::If {
-                    cond,
-                    then_body,
-                    else_body,
-                } => {
-                    let cond_val = self.eval_expr(cond, prog)?;
-                    let stmts = if cond_val != 0 { then_body } else { else_body };
-                    for st in stmts {
-                        match st {
-                            Stmt::Assign { var, expr } => {
-                                let val = self.eval_expr(expr, prog)?;
-                                self.vars.insert(var.clone(), val);
-                            }
-                            Stmt::Print(expr) => {
-                                let out = match expr {
-                                    Expr::LiteralStr(s) => s.clone(),
-                                    Expr::LiteralInt(i) => i.to_string(),
-                                    _ => self.eval_expr(expr, prog)?.to_string(),
-                                };
-                                outputs.push(out);
-                            }
-                            _ => {}
-                        }
+                if !self.locals().contains_key(var)
+                    && let Some(global) = self.globals.get_mut(var)
+                {
+                    *global = val;
+                } else {
+                    self.locals().insert(var.clone(), val);
+                }
+            }
+            StmtKind::Print(expr) => {
+                let out = self.eval_expr(expr, prog)?.to_string();
+                self.printed += out.len() + 1;
+                if let Some(max) = self.limits.output_bytes
+                    && self.printed > max
+                {
+                    return Err(self.exceeded(Limit::OutputBytes(max), stmt.span));
+                }
+                writeln!(self.out, "{}", out)
+                    .and_then(|_| self.out.flush())
+                    .map_err(|e| {
+                        self.error_at(Code::Io, format!("Cannot write output: {}", e), stmt.span)
+                    })?;
+            }
+            StmtKind::If {
+                cond,
+                then_body,
+                else_body,
+            } => {
+                let cond_val = self.eval_expr(cond, prog)?;
+                let branch = if cond_val.is_truthy() {
+                    then_body
+                } else {
+                    else_body
+                };
+                return self.exec_block(branch, prog);
+            }
+            StmtKind::Loop { var, count, body } => {
+                let times = self.eval_int(count, "Loop count", prog)?;
+                // zero iterations is fine, a negative count is almost certainly a bug
+                if times < 0 {
+                    return Err(self.error_at(
+                        Code::InvalidLoop,
+                        format!("Loop count must not be negative, got {}", times),
+                        count.span,
+                    ));
+                }
+                for i in 0..times {
+                    self.iteration(i as u64 + 1, stmt.span)?;
+                    if let Some(var) = var {
+                        self.locals().insert(var.clone(), Value::Int(i));
                     }
+                    match self.exec_block(body, prog)? {
+                        Flow::Break => break,
+                        flow @ Flow::Return(_) => return Ok(flow),
+                        Flow::Next | Flow::Continue => {}
+                    }
+                }
+            }
+            StmtKind::For {
+                var,
+                from,
+                to,
+                step,
+                inclusive,
+                body,
+            } => {
+                let mut i = self.eval_int(from, "For range start", prog)?;
+                let end = self.eval_int(to, "For range end", prog)?;
+                let step_val = self.eval_int(step, "For step", prog)?;
+                if step_val == 0 {
+                    return Err(self.error_at(
+                        Code::InvalidLoop,
+                        "For step must not be zero".to_string(),
+                        step.span,
+                    ));
                 }
-                Stmt::Loop { count, body } => {
-                    // evaluate loop count
-                    let times = self.eval_expr(count, prog)?;
-                    for _ in 0..times {
-                        // execute each statement in loop body
-                        for st in body {
-                            match st {
-                                Stmt::Assign { var, expr } => {
-                                    let val = self.eval_expr(expr, prog)?;
-                                    self.vars.insert(var.clone(), val);
-                                }
-                                Stmt::Print(expr) => {
-                                    let out = match expr {
-                                        Expr::LiteralStr(s) => s.clone(),
-                                        Expr::LiteralInt(i) => i.to_string(),
-                                        _ => self.eval_expr(expr, prog)?.to_string(),
-                                    };
-                                    outputs.push(out);
-                                }
-                                Stmt::Loop { .. } => {
-                                    let _nested = Stmt::Loop {
-                                        count: count.clone(),
-                                        body: body.clone(),
-                                    };
-                                    // recurse by adding statement
-                                    // could call run but simpler: ignore nested for now
-                                }
-                                Stmt::Call(name, args) => {
-                                    let expr = Expr::Call(name.clone(), args.clone());
-                                    let _ = self.eval_expr(&expr, prog)?;
-                                }
-                                Stmt::Return(_) => {}
-                                Stmt::If {
-                                    cond,
-                                    then_body,
-                                    else_body,
-                                } => {
-                                    // reuse existing semantics: evaluate one iteration of if
-                                    let cond_val = self.eval_expr(cond, prog)?;
-                                    let branch = if cond_val != 0 { then_body } else { else_body };
-                                    for b in branch {
-                                        match b {
-                                            Stmt::Assign { var, expr } => {
-                                                let v = self.eval_expr(expr, prog)?;
-                                                self.vars.insert(var.clone(), v);
-                                            }
-                                            Stmt::Print(expr) => {
-                                                let o = match expr {
-                                                    Expr::LiteralStr(s) => s.clone(),
-                                                    Expr::LiteralInt(i) => i.to_string(),
-                                                    _ => self.eval_expr(expr, prog)?.to_string(),
-                                                };
-                                                outputs.push(o);
-                                            }
-                                            _ => {}
-                                        }
-                                    }
-                                }
-                            }
-                        }
+                let in_range = |i: i64| match (step_val > 0, *inclusive) {
+                    (true, false) => i < end,
+                    (true, true) => i <= end,
+                    (false, false) => i > end,
+                    (false, true) => i >= end,
+                };
+                let mut n = 0;
+                while in_range(i) {
+                    n += 1;
+                    self.iteration(n, stmt.span)?;
+                    self.locals().insert(var.clone(), Value::Int(i));
+                    match self.exec_block(body, prog)? {
+                        Flow::Break => break,
+                        flow @ Flow::Return(_) => return Ok(flow),
+                        Flow::Next | Flow::Continue => {}
+                    }
+                    // stepping past i64 bounds means the range is exhausted
+                    match i.checked_add(step_val) {
+                        Some(next) => i = next,
+                        None => break,
                     }
                 }
-                Stmt::Return(_) => { /* ignore return in main */ }
-                Stmt::Call(name, args) => {
-                    // Evaluate standalone call, errors on undefined function
-                    let expr = Expr::Call(name.clone(), args.clone());
-                    let _ = self.eval_expr(&expr, prog)?;
+            }
+            StmtKind::While { cond, body } => {
+                let mut n = 0;
+                while self.eval_expr(cond, prog)?.is_truthy() {
+                    n += 1;
+                    self.iteration(n, stmt.span)?;
+                    match self.exec_block(body, prog)? {
+                        Flow::Break => break,
+                        flow @ Flow::Return(_) => return Ok(flow),
+                        Flow::Next | Flow::Continue => {}
+                    }
                 }
             }
+            StmtKind::Break => return Ok(Flow::Break),
+            StmtKind::Continue => return Ok(Flow::Continue),
+            StmtKind::Return(expr) => {
+                let val = self.eval_expr(expr, prog)?;
+                return Ok(Flow::Return(val));
+            }
+            StmtKind::Call(name, args) => {
+                // Evaluate standalone call, errors on undefined function
+                self.eval_call(name, args, stmt.span, prog)?;
+            }
         }
-        Ok(outputs)
+        Ok(Flow::Next)
     }
 
     /// Evaluate an expression; supports function calls to user-defined functions
-    fn eval_expr(&mut self, expr: &Expr, prog: &Program) -> Result<i64, XplError> {
-        match expr {
-            Expr::BinaryOp(op, l, r) => {
+    fn eval_expr(&mut self, expr: &Expr, prog: &Program) -> Result<Value, XplError> {
+        self.step(expr.span)?;
+        self.nested(expr.span, |vm| vm.eval_kind(expr, prog))
+    }
+
+    fn eval_kind(&mut self, expr: &Expr, prog: &Program) -> Result<Value, XplError> {
+        match &expr.kind {
+            // logical operators short-circuit: the right side only runs when needed
+            ExprKind::BinaryOp(BinOp::And, l, r) => {
+                if !self.eval_expr(l, prog)?.is_truthy() {
+                    return Ok(Value::Bool(false));
+                }
+                Ok(Value::Bool(self.eval_expr(r, prog)?.is_truthy()))
+            }
+            ExprKind::BinaryOp(BinOp::Or, l, r) => {
+                if self.eval_expr(l, prog)?.is_truthy() {
+                    return Ok(Value::Bool(true));
+                }
+                Ok(Value::Bool(self.eval_expr(r, prog)?.is_truthy()))
+            }
+            ExprKind::BinaryOp(op, l, r) => {
                 let left = self.eval_expr(l, prog)?;
                 let right = self.eval_expr(r, prog)?;
-                let res = match op {
-                    BinOp::Add => left + right,
-                    BinOp::Subtract => left - right,
-                    BinOp::Multiply => left * right,
-                    BinOp::Divide => {
-                        if right == 0 {
-                            return Err(XplError::Semantic {
-                                msg: "Division by zero".to_string(),
-                                file: self.file.clone(),
-                                line: 0,
-                                col: 0,
-                            });
-                        }
-                        left / right
-                    }
-                    BinOp::Modulus => {
-                        if right == 0 {
-                            return Err(XplError::Semantic {
-                                msg: "Division by zero".to_string(),
-                                file: self.file.clone(),
-                                line: 0,
-                                col: 0,
-                            });
-                        }
-                        left % right
-                    }
-                };
-                return Ok(res);
+                self.binary_op(op, left, right, expr, prog.arithmetic)
             }
-            Expr::LiteralInt(i) => Ok(*i),
-            Expr::VarRef(name) => match self.vars.get(name) {
-                Some(v) => Ok(*v),
-                None => {
-                    let (line, col) = self.find_pos(name);
-                    Err(XplError::Semantic {
-                        msg: format!("Undefined variable {}", name),
-                        file: self.file.clone(),
-                        line: line + 1,
-                        col: col + 1,
-                    })
-                }
+            ExprKind::UnaryOp(UnOp::Negate, e) => match self.eval_expr(e, prog)? {
+                Value::Int(i) => match prog.arithmetic {
+                    Arithmetic::Checked => i.checked_neg().map(Value::Int).ok_or_else(|| {
+                        self.error_at(
+                            Code::Overflow,
+                            format!("Integer overflow in -({})", i),
+                            expr.span,
+                        )
+                    }),
+                    Arithmetic::Wrapping => Ok(Value::Int(i.wrapping_neg())),
+                    Arithmetic::Saturating => Ok(Value::Int(i.saturating_neg())),
+                    Arithmetic::Bigint => Ok(Value::from_bigint(-BigInt::from(i))),
+                },
+                Value::BigInt(i) => Ok(Value::from_bigint(-i)),
+                Value::Float(f) => Ok(Value::Float(-f)),
+                Value::Decimal(d) => Ok(Value::Decimal(-d)),
+                other => Err(self.error_at(
+                    Code::TypeMismatch,
+                    format!("Cannot negate a value of type {}", other.type_name()),
+                    expr.span,
+                )),
             },
-            Expr::Call(name, args) => {
-                // Evaluate argument expressions
-                let mut arg_vals = Vec::new();
-                for a in args {
-                    arg_vals.push(self.eval_expr(a, prog)?);
+            ExprKind::UnaryOp(UnOp::Not, e) => {
+                Ok(Value::Bool(!self.eval_expr(e, prog)?.is_truthy()))
+            }
+            ExprKind::LiteralInt(i) => Ok(Value::Int(*i)),
+            ExprKind::LiteralBigInt(i) => Ok(Value::BigInt(i.clone())),
+            ExprKind::LiteralFloat(f) => Ok(Value::Float(*f)),
+            ExprKind::LiteralDecimal(d) => Ok(Value::Decimal(*d)),
+            ExprKind::LiteralStr(s) => Ok(Value::Str(s.clone())),
+            ExprKind::LiteralBool(b) => Ok(Value::Bool(*b)),
+            ExprKind::LiteralNull => Ok(Value::Null),
+            ExprKind::VarRef(name) => {
+                let local = self.frames.last().and_then(|f| f.locals.get(name));
+                match local.or_else(|| self.globals.get(name)) {
+                    Some(v) => Ok(v.clone()),
+                    None => Err(self.error_at(
+                        Code::UndefinedVariable,
+                        format!("Undefined variable {}", name),
+                        expr.span,
+                    )),
                 }
-                // Call user-defined function
-                self.call_function(prog, name, arg_vals)
             }
-            _ => Err(XplError::Semantic {
-                msg: "Unsupported expression in eval".to_string(),
-                file: self.file.clone(),
-                line: 0,
-                col: 0,
-            }),
+            ExprKind::Call(name, args) => self.eval_call(name, args, expr.span, prog),
         }
     }
 
-    /// Call a user-defined function and return its integer return value
+    /// Evaluate the arguments of a call written at `span`, then make the call
+    fn eval_call(
+        &mut self,
+        name: &str,
+        args: &[Expr],
+        span: Span,
+        prog: &Program,
+    ) -> Result<Value, XplError> {
+        let mut arg_vals = Vec::new();
+        for a in args {
+            arg_vals.push(self.eval_expr(a, prog)?);
+        }
+        self.call_function(prog, name, arg_vals, span)
+    }
+
+    /// Evaluate an expression that must produce an int, naming it in the type error
+    fn eval_int(&mut self, expr: &Expr, what: &str, prog: &Program) -> Result<i64, XplError> {
+        match self.eval_expr(expr, prog)? {
+            Value::Int(n) => Ok(n),
+            other => Err(self.error_at(
+                Code::TypeMismatch,
+                format!("{} must be int, got {}", what, other.type_name()),
+                expr.span,
+            )),
+        }
+    }
+
+    /// Apply a non-logical binary operator to two evaluated operands
+    fn binary_op(
+        &self,
+        op: &BinOp,
+        left: Value,
+        right: Value,
+        expr: &Expr,
+        arithmetic: Arithmetic,
+    ) -> Result<Value, XplError> {
+        use Value::{BigInt, Bool, Decimal, Float, Int, Str};
+        let overflow = |kind: &str| {
+            self.error_at(
+                Code::Overflow,
+                format!("{} overflow in {} {} {}", kind, left, op, right),
+                expr.span,
+            )
+        };
+        let res = match (op, &left, &right) {
+            (BinOp::Equal, _, _) => Bool(values_equal(&left, &right)),
+            (BinOp::NotEqual, _, _) => Bool(!values_equal(&left, &right)),
+            // `+` with a string on either side concatenates display forms
+            (BinOp::Add, Str(_), _) | (BinOp::Add, _, Str(_)) => Str(format!("{}{}", left, right)),
+            (BinOp::Divide | BinOp::Modulus, Int(_) | BigInt(_) | Decimal(_), _)
+                if is_exact_zero(&right) =>
+            {
+                return Err(self.error_at(
+                    Code::DivisionByZero,
+                    "Division by zero".to_string(),
+                    expr.span,
+                ));
+            }
+            (
+                BinOp::Add | BinOp::Subtract | BinOp::Multiply | BinOp::Divide | BinOp::Modulus,
+                Int(a),
+                Int(b),
+            ) => match int_op(op, *a, *b, arithmetic) {
+                Some(n) => Int(n),
+                None if arithmetic == Arithmetic::Bigint => big_op(op, &(*a).into(), &(*b).into()),
+                None => return Err(overflow("Integer")),
+            },
+            (_, Int(a), Int(b)) => Bool(compare(op, a.cmp(b))),
+            (
+                BinOp::Add | BinOp::Subtract | BinOp::Multiply | BinOp::Divide | BinOp::Modulus,
+                Int(_) | BigInt(_),
+                Int(_) | BigInt(_),
+            ) => big_op(op, &as_bigint(&left), &as_bigint(&right)),
+            (
+                BinOp::Add | BinOp::Subtract | BinOp::Multiply | BinOp::Divide | BinOp::Modulus,
+                Int(_) | BigInt(_) | Decimal(_),
+                Int(_) | BigInt(_) | Decimal(_),
+            ) => match (as_decimal(&left), as_decimal(&right)) {
+                (Some(a), Some(b)) => {
+                    Decimal(decimal_op(op, a, b).ok_or_else(|| overflow("Decimal"))?)
+                }
+                _ => return Err(overflow("Decimal")),
+            },
+            // decimals and floats only mix in comparisons, to keep decimal results exact
+            (
+                BinOp::Add | BinOp::Subtract | BinOp::Multiply | BinOp::Divide | BinOp::Modulus,
+                Int(_) | BigInt(_) | Float(_),
+                Int(_) | BigInt(_) | Float(_),
+            ) => {
+                let (a, b) = (as_float(&left), as_float(&right));
+                match op {
+                    BinOp::Add => Float(a + b),
+                    BinOp::Subtract => Float(a - b),
+                    BinOp::Multiply => Float(a * b),
+                    BinOp::Divide => Float(a / b),
+                    _ => Float(a % b),
+                }
+            }
+            (
+                BinOp::Less | BinOp::LessEqual | BinOp::Greater | BinOp::GreaterEqual,
+                Int(_) | BigInt(_) | Float(_) | Decimal(_),
+                Int(_) | BigInt(_) | Float(_) | Decimal(_),
+            ) => match compare_numbers(&left, &right) {
+                Some(ord) => Bool(compare(op, ord)),
+                // NaN compares false with everything
+                None => Bool(false),
+            },
+            (
+                BinOp::Less | BinOp::LessEqual | BinOp::Greater | BinOp::GreaterEqual,
+                Str(a),
+                Str(b),
+            ) => Bool(compare(op, a.cmp(b))),
+            _ => {
+                return Err(self.error_at(
+                    Code::TypeMismatch,
+                    format!(
+                        "Cannot apply '{}' to {} and {}",
+                        op,
+                        left.type_name(),
+                        right.type_name()
+                    ),
+                    expr.span,
+                ));
+            }
+        };
+        Ok(res)
+    }
+
+    /// Call a user-defined function, native or builtin from a call written at `span`
     fn call_function(
         &mut self,
         prog: &Program,
         name: &str,
-        args: Vec<i64>,
-    ) -> Result<i64, XplError> {
-        let func = prog.functions.get(name).ok_or_else(|| {
-            let (line, col) = self.find_pos(name);
-            XplError::Semantic {
-                msg: format!("Undefined function {}", name),
-                file: self.file.clone(),
-                line: line + 1,
-                col: col + 1,
+        args: Vec<Value>,
+        span: Span,
+    ) -> Result<Value, XplError> {
+        let located = |code: Code, msg: String| self.error_at(code, msg, span);
+        let natives = &self.natives;
+        let callee = Callee::lookup(prog, name, |n| natives.get(n).map(|(sig, _)| sig))
+            .ok_or_else(|| {
+                located(
+                    Code::UndefinedFunction,
+                    format!("Undefined function {}", name),
+                )
+            })?;
+        callee
+            .check_arity(args.len())
+            .map_err(|msg| located(Code::ArgumentCount, msg))?;
+        match callee {
+            Callee::Script(_) => {}
+            Callee::Native(sig) => {
+                let args = sig
+                    .bind(args)
+                    .map_err(|msg| located(Code::TypeMismatch, msg))?;
+                let f = &natives[name].1;
+                return f(&args).map_err(|e| match e {
+                    // errors the native did not locate itself belong to the call
+                    XplError::Semantic {
+                        code, msg, line: 0, ..
+                    } => located(code, msg),
+                    other => other,
+                });
             }
-        })?;
-        if func.params.len() != args.len() {
-            return Err(XplError::Semantic {
-                msg: format!(
-                    "Expected {} args for function '{}', got {}",
-                    func.params.len(),
-                    name,
-                    args.len()
-                ),
-                file: self.file.clone(),
-                line: 0,
-                col: 0,
-            });
+            // the arguments belong to the run, not to the builtin library
+            Callee::Builtin(_) if name == "args" => {
+                return Ok(Value::List(
+                    self.args.iter().cloned().map(Value::Str).collect(),
+                ));
+            }
+            Callee::Builtin(_) => {
+                let value =
+                    builtins::call(name, &args).map_err(|msg| located(Code::BuiltinFailed, msg))?;
+                // `int` may give a result beyond 64 bits, which only bigint programs can hold
+                if let Value::BigInt(_) = &value
+                    && prog.arithmetic != Arithmetic::Bigint
+                {
+                    return Err(located(
+                        Code::Overflow,
+                        format!("Integer overflow in {}({})", name, args[0]),
+                    ));
+                }
+                return Ok(value);
+            }
+        }
+        // fail before the host's stack runs out on runaway recursion
+        if let Some(max) = self.limits.call_depth
+            && self.frames.len() >= max
+        {
+            return Err(self.exceeded(Limit::CallDepth(max), span));
         }
-        // Setup local frame
-        let mut locals = std::collections::HashMap::new();
+        let func = &prog.functions[name];
+        // Setup local frame, widening ints passed for float and decimal params
+        let mut locals = HashMap::new();
         for (p, v) in func.params.iter().zip(args) {
+            let v = match &p.ptype {
+                Some(ty) => v.widen(ty),
+                None => v,
+            };
             locals.insert(p.name.clone(), v);
         }
-        // Save global vars
-        let saved = std::mem::take(&mut self.vars);
-        // Use locals as vars for this call
-        self.vars = locals;
-        // Execute function body
-        let mut ret = 0;
-        for stmt in &func.body {
-            match stmt {
-                Stmt::Return(expr) => {
-                    ret = self.eval_expr(expr, prog)?;
-                    break;
-                }
-                _ => {}
+        self.frames.push(Frame::new(func, locals));
+        // Execute function body; falling off the end returns null
+        let res = self.exec_block(&func.body, prog);
+        self.frames.pop();
+        match res? {
+            Flow::Return(v) => Ok(v),
+            // the parser rejects break/continue outside loops, so only Next remains
+            _ => Ok(Value::Null),
+        }
+    }
+}
+
+/// Equality across types: numbers compare by value, other mismatched types are unequal
+fn values_equal(left: &Value, right: &Value) -> bool {
+    match (left, right) {
+        (Value::Int(a), Value::Int(b)) => a == b,
+        (
+            Value::Int(_) | Value::BigInt(_) | Value::Float(_) | Value::Decimal(_),
+            Value::Int(_) | Value::BigInt(_) | Value::Float(_) | Value::Decimal(_),
+        ) => compare_numbers(left, right) == Some(Ordering::Equal),
+        _ => left == right,
+    }
+}
+
+/// Order two numbers by value, exactly unless a float is involved; None when one is NaN
+fn compare_numbers(left: &Value, right: &Value) -> Option<Ordering> {
+    use Value::{BigInt, Decimal, Int};
+    match (left, right) {
+        (Int(_) | BigInt(_), Int(_) | BigInt(_)) => Some(as_bigint(left).cmp(&as_bigint(right))),
+        (Int(_) | BigInt(_) | Decimal(_), Int(_) | BigInt(_) | Decimal(_)) => {
+            match (as_decimal(left), as_decimal(right)) {
+                (Some(a), Some(b)) => Some(a.cmp(&b)),
+                // an int beyond the decimal range is bigger than any decimal
+                _ => as_float(left).partial_cmp(&as_float(right)),
             }
         }
-        // Restore global vars
-        self.vars = saved;
-        Ok(ret)
+        _ => as_float(left).partial_cmp(&as_float(right)),
+    }
+}
+
+/// Whether a divisor is an int or decimal zero; dividing floats by zero gives infinity
+fn is_exact_zero(v: &Value) -> bool {
+    match v {
+        Value::Int(i) => *i == 0,
+        Value::BigInt(i) => *i == BigInt::ZERO,
+        Value::Decimal(d) => d.is_zero(),
+        _ => false,
+    }
+}
+
+fn as_float(v: &Value) -> f64 {
+    match v {
+        Value::Int(i) => *i as f64,
+        Value::BigInt(i) => i.to_f64().unwrap_or(f64::NAN),
+        Value::Float(f) => *f,
+        Value::Decimal(d) => d.to_f64().unwrap_or(f64::NAN),
+        _ => unreachable!("only called on numbers"),
+    }
+}
+
+fn as_bigint(v: &Value) -> BigInt {
+    match v {
+        Value::Int(i) => (*i).into(),
+        Value::BigInt(i) => i.clone(),
+        _ => unreachable!("only called on ints"),
+    }
+}
+
+/// An int or decimal as a decimal, or None for an int beyond the decimal range
+fn as_decimal(v: &Value) -> Option<Decimal> {
+    match v {
+        Value::Int(i) => Some((*i).into()),
+        Value::BigInt(i) => Decimal::from_str_exact(&i.to_string()).ok(),
+        Value::Decimal(d) => Some(*d),
+        _ => unreachable!("only called on ints and decimals"),
+    }
+}
+
+/// Apply an arithmetic operator to ints of any size. The divisor is never zero.
+fn big_op(op: &BinOp, a: &BigInt, b: &BigInt) -> Value {
+    Value::from_bigint(match op {
+        BinOp::Add => a + b,
+        BinOp::Subtract => a - b,
+        BinOp::Multiply => a * b,
+        // both round toward zero, like 64-bit ints
+        BinOp::Divide => a / b,
+        BinOp::Modulus => a % b,
+        _ => unreachable!("{} is not an arithmetic operator", op),
+    })
+}
+
+/// Apply an arithmetic operator to decimals, or None when the result is out of range.
+/// The divisor is never zero.
+fn decimal_op(op: &BinOp, a: Decimal, b: Decimal) -> Option<Decimal> {
+    match op {
+        BinOp::Add => a.checked_add(b),
+        BinOp::Subtract => a.checked_sub(b),
+        BinOp::Multiply => a.checked_mul(b),
+        BinOp::Divide => a.checked_div(b),
+        BinOp::Modulus => a.checked_rem(b),
+        _ => unreachable!("{} is not an arithmetic operator", op),
+    }
+}
+
+/// Apply an arithmetic operator to ints under the program's overflow policy,
+/// or None when the result overflows in checked or bigint mode. The divisor is never zero.
+fn int_op(op: &BinOp, a: i64, b: i64, arithmetic: Arithmetic) -> Option<i64> {
+    use Arithmetic::{Bigint, Checked, Saturating, Wrapping};
+    Some(match (op, arithmetic) {
+        (BinOp::Add, Checked | Bigint) => a.checked_add(b)?,
+        (BinOp::Add, Wrapping) => a.wrapping_add(b),
+        (BinOp::Add, Saturating) => a.saturating_add(b),
+        (BinOp::Subtract, Checked | Bigint) => a.checked_sub(b)?,
+        (BinOp::Subtract, Wrapping) => a.wrapping_sub(b),
+        (BinOp::Subtract, Saturating) => a.saturating_sub(b),
+        (BinOp::Multiply, Checked | Bigint) => a.checked_mul(b)?,
+        (BinOp::Multiply, Wrapping) => a.wrapping_mul(b),
+        (BinOp::Multiply, Saturating) => a.saturating_mul(b),
+        // only the smallest int divided by -1 overflows
+        (BinOp::Divide, Checked | Bigint) => a.checked_div(b)?,
+        (BinOp::Divide, Wrapping) => a.wrapping_div(b),
+        (BinOp::Divide, Saturating) => a.saturating_div(b),
+        // the remainder is always in range; only computing it for i64::MIN % -1 overflows
+        (BinOp::Modulus, _) => a.wrapping_rem(b),
+        _ => unreachable!("{} is not an arithmetic operator", op),
+    })
+}
+
+/// Evaluate an ordering operator given how its operands compare
+fn compare(op: &BinOp, ord: Ordering) -> bool {
+    match op {
+        BinOp::Less => ord.is_lt(),
+        BinOp::LessEqual => ord.is_le(),
+        BinOp::Greater => ord.is_gt(),
+        BinOp::GreaterEqual => ord.is_ge(),
+        _ => unreachable!("not an ordering operator: {}", op),
     }
 }